@@ -0,0 +1,92 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+use tempfile::Builder;
+
+/// Temporary files that have yet to be renamed into place, for the signal handler to remove
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Keeps a temporary file in `PENDING` for as long as it exists
+struct Pending(PathBuf);
+
+impl Pending {
+    fn new(path: &Path) -> Pending {
+        PENDING.lock().unwrap_or_else(|err| err.into_inner()).push(path.to_owned());
+        Pending(path.to_owned())
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        PENDING
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .retain(|path| path != &self.0);
+    }
+}
+
+/// Removes unfinished temporary files when interrupted by SIGINT, SIGTERM or SIGHUP, which would
+/// otherwise skip their destructors
+pub fn remove_on_signal() -> io::Result<()> {
+    ctrlc::set_handler(|| {
+        for path in PENDING.lock().unwrap_or_else(|err| err.into_inner()).iter() {
+            let _ = fs::remove_file(path);
+        }
+        eprintln!("\nInterrupted, the previous output was left in place");
+        process::exit(130);
+    })
+    .map_err(io::Error::other)
+}
+
+/// Replaces the file at `path` with `data`, so that it holds either the old or the new contents
+/// even if writing fails or the system loses power halfway through
+pub fn write(path: &Path, data: &[u8]) -> io::Result<()> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    // Keep the permissions of the file being replaced, rather than the temporary file's 0600
+    let permissions = match fs::metadata(path) {
+        Ok(metadata) => metadata.permissions(),
+        Err(_) => fs::Permissions::from_mode(0o644),
+    };
+
+    // The temporary file must be on the same filesystem for the rename to be atomic
+    let mut file = Builder::new()
+        .prefix(".sigen-")
+        .suffix(".tmp")
+        .permissions(permissions)
+        .tempfile_in(directory)?;
+    let _pending = Pending::new(file.path());
+    file.write_all(data)?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|err| err.error)?;
+
+    // Make the rename itself durable
+    File::open(directory)?.sync_all()
+}
+
+/// Copies a file with the same guarantees as `write`
+pub fn copy(from: &Path, to: &Path) -> io::Result<()> {
+    write(to, &fs::read(from)?)
+}
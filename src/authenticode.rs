@@ -0,0 +1,571 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use cms::cert::{CertificateChoices, IssuerAndSerialNumber};
+use cms::content_info::{CmsVersion, ContentInfo};
+use cms::signed_data::{EncapsulatedContentInfo, SignedData, SignerIdentifier, SignerInfo};
+use der::asn1::{ObjectIdentifier, OctetString, SetOfVec};
+use der::{Any, Decode, DecodePem, Encode, Sequence};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use spki::AlgorithmIdentifierOwned;
+use x509_cert::attr::Attribute;
+use x509_cert::Certificate;
+
+const ID_SIGNED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+const ID_CONTENT_TYPE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.3");
+const ID_MESSAGE_DIGEST: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+const ID_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1");
+const RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const SHA256_WITH_RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11");
+const SHA384_WITH_RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.12");
+const SHA512_WITH_RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.13");
+
+const SPC_INDIRECT_DATA_OBJID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.4");
+const SPC_SP_OPUS_INFO_OBJID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.12");
+const SPC_PE_IMAGE_DATA_OBJID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.15");
+
+/// Maximum number of intermediate certificates followed when looking for a trusted issuer
+const MAX_CHAIN_LENGTH: usize = 8;
+
+/// DER encoding of an `SpcPeImageData` with no flags and the customary `<<<Obsolete>>>` file link
+const SPC_PE_IMAGE_DATA: &[u8] = &[
+    0x30, 0x25, 0x03, 0x01, 0x00, 0xa0, 0x20, 0xa2, 0x1e, 0x80, 0x1c, 0x00, 0x3c, 0x00, 0x3c, 0x00, 0x3c, 0x00, 0x4f,
+    0x00, 0x62, 0x00, 0x73, 0x00, 0x6f, 0x00, 0x6c, 0x00, 0x65, 0x00, 0x74, 0x00, 0x65, 0x00, 0x3e, 0x00, 0x3e, 0x00,
+    0x3e,
+];
+
+#[derive(Sequence)]
+struct SpcAttributeTypeAndOptionalValue {
+    value_type: ObjectIdentifier,
+    value: Any,
+}
+
+#[derive(Sequence)]
+struct DigestInfo {
+    digest_algorithm: AlgorithmIdentifierOwned,
+    digest: OctetString,
+}
+
+#[derive(Sequence)]
+struct SpcIndirectDataContent {
+    data: SpcAttributeTypeAndOptionalValue,
+    message_digest: DigestInfo,
+}
+
+fn invalid_data(what: &str, err: impl Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", what, err))
+}
+
+fn sha256_algorithm() -> AlgorithmIdentifierOwned {
+    AlgorithmIdentifierOwned {
+        oid: ID_SHA256,
+        parameters: Some(Any::null()),
+    }
+}
+
+fn attribute(oid: ObjectIdentifier, value: Any) -> der::Result<Attribute> {
+    Ok(Attribute {
+        oid,
+        values: SetOfVec::try_from(vec![value])?,
+    })
+}
+
+fn malformed_signature(err: impl Display) -> io::Error {
+    invalid_data("Malformed signature", err)
+}
+
+fn public_key(certificate: &Certificate) -> io::Result<RsaPublicKey> {
+    certificate
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .map_err(|err| invalid_data("Failed to encode public key", err))
+        .and_then(|der| {
+            RsaPublicKey::from_public_key_der(&der)
+                .map_err(|err| invalid_data("Certificate does not contain an RSA key", err))
+        })
+}
+
+/// Checks a PKCS#1 v1.5 signature made with one of the SHA-2 based RSA algorithms
+fn verify_rsa(key: &RsaPublicKey, algorithm: ObjectIdentifier, message: &[u8], signature: &[u8]) -> bool {
+    let result = match algorithm {
+        RSA_ENCRYPTION | SHA256_WITH_RSA_ENCRYPTION => {
+            key.verify(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(message), signature)
+        }
+        SHA384_WITH_RSA_ENCRYPTION => key.verify(Pkcs1v15Sign::new::<Sha384>(), &Sha384::digest(message), signature),
+        SHA512_WITH_RSA_ENCRYPTION => key.verify(Pkcs1v15Sign::new::<Sha512>(), &Sha512::digest(message), signature),
+        _ => return false,
+    };
+    result.is_ok()
+}
+
+/// Checks whether `certificate` was signed by `issuer`
+fn issued_by(certificate: &Certificate, issuer: &Certificate) -> bool {
+    if certificate.tbs_certificate.issuer != issuer.tbs_certificate.subject {
+        return false;
+    }
+
+    let (key, tbs) = match (public_key(issuer), certificate.tbs_certificate.to_der()) {
+        (Ok(key), Ok(tbs)) => (key, tbs),
+        _ => return false,
+    };
+    verify_rsa(
+        &key,
+        certificate.signature_algorithm.oid,
+        &tbs,
+        certificate.signature.raw_bytes(),
+    )
+}
+
+/// Certificates and image digests that are trusted to sign or identify images, such as the
+/// contents of the firmware's db
+#[derive(Default)]
+pub struct TrustStore {
+    pub certificates: Vec<Certificate>,
+    pub digests: Vec<Vec<u8>>,
+}
+
+impl TrustStore {
+    fn is_trusted(&self, certificate: &Certificate) -> bool {
+        self.certificates
+            .iter()
+            .any(|trusted| trusted == certificate || issued_by(certificate, trusted))
+    }
+}
+
+/// A decoded Authenticode signature
+struct ParsedSignature {
+    signed_data: SignedData,
+    indirect_data: SpcIndirectDataContent,
+}
+
+impl ParsedSignature {
+    fn parse(signed_data: &[u8]) -> io::Result<ParsedSignature> {
+        let content_info = ContentInfo::from_der(signed_data).map_err(malformed_signature)?;
+        if content_info.content_type != ID_SIGNED_DATA {
+            return Err(malformed_signature("not a SignedData structure"));
+        }
+        let signed_data: SignedData = content_info.content.decode_as().map_err(malformed_signature)?;
+
+        let encap = &signed_data.encap_content_info;
+        let indirect_data = match (&encap.econtent, encap.econtent_type == SPC_INDIRECT_DATA_OBJID) {
+            (Some(content), true) => content.decode_as().map_err(malformed_signature)?,
+            _ => return Err(malformed_signature("missing SpcIndirectDataContent")),
+        };
+
+        Ok(ParsedSignature {
+            signed_data,
+            indirect_data,
+        })
+    }
+
+    /// Content octets of the SpcIndirectDataContent, which is what the signer actually signs
+    fn content(&self) -> &[u8] {
+        match &self.signed_data.encap_content_info.econtent {
+            Some(content) => content.value(),
+            None => &[],
+        }
+    }
+
+    fn certificates(&self) -> Vec<&Certificate> {
+        self.signed_data
+            .certificates
+            .iter()
+            .flat_map(|set| set.0.iter())
+            .filter_map(|choice| match choice {
+                CertificateChoices::Certificate(certificate) => Some(certificate),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn signer_info(&self) -> io::Result<&SignerInfo> {
+        self.signed_data
+            .signer_infos
+            .0
+            .iter()
+            .next()
+            .ok_or_else(|| malformed_signature("no signer information"))
+    }
+
+    fn signer(&self) -> io::Result<&Certificate> {
+        let signer = match &self.signer_info()?.sid {
+            SignerIdentifier::IssuerAndSerialNumber(id) => self.certificates().into_iter().find(|c| {
+                c.tbs_certificate.issuer == id.issuer && c.tbs_certificate.serial_number == id.serial_number
+            }),
+            SignerIdentifier::SubjectKeyIdentifier(_) => None,
+        };
+        signer.ok_or_else(|| malformed_signature("signer certificate is not embedded"))
+    }
+}
+
+/// Details of an Authenticode signature, for display purposes
+pub struct SignatureInfo {
+    pub signer: String,
+    pub issuer: String,
+    pub serial: String,
+    pub digest_algorithm: String,
+    pub digest: Vec<u8>,
+}
+
+/// Decodes an Authenticode signature without verifying it
+pub fn describe(signed_data: &[u8]) -> io::Result<SignatureInfo> {
+    let parsed = ParsedSignature::parse(signed_data)?;
+    let signer = &parsed.signer()?.tbs_certificate;
+    let digest_info = &parsed.indirect_data.message_digest;
+
+    Ok(SignatureInfo {
+        signer: signer.subject.to_string(),
+        issuer: signer.issuer.to_string(),
+        serial: hex(signer.serial_number.as_bytes()),
+        digest_algorithm: match digest_info.digest_algorithm.oid {
+            ID_SHA256 => "sha256".to_owned(),
+            oid => oid.to_string(),
+        },
+        digest: digest_info.digest.as_bytes().to_vec(),
+    })
+}
+
+/// Verifies an Authenticode signature of an image with the given SHA-256 Authenticode digest,
+/// returning the subject of the trusted certificate that signed it
+pub fn verify(signed_data: &[u8], image_digest: &[u8], trust: &TrustStore) -> io::Result<String> {
+    let parsed = ParsedSignature::parse(signed_data)?;
+
+    let digest_info = &parsed.indirect_data.message_digest;
+    if digest_info.digest_algorithm.oid != ID_SHA256 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unsupported digest algorithm {}", digest_info.digest_algorithm.oid),
+        ));
+    }
+    if digest_info.digest.as_bytes() != image_digest {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Digest mismatch: image hashes to {}, but the signature covers {}",
+                hex(image_digest),
+                hex(digest_info.digest.as_bytes())
+            ),
+        ));
+    }
+
+    let signer_info = parsed.signer_info()?;
+    if signer_info.digest_alg.oid != ID_SHA256 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unsupported digest algorithm {}", signer_info.digest_alg.oid),
+        ));
+    }
+    let signer = parsed.signer()?;
+
+    let signed_attrs = signer_info
+        .signed_attrs
+        .as_ref()
+        .ok_or_else(|| malformed_signature("no signed attributes"))?;
+    let message_digest = signed_attrs
+        .iter()
+        .find(|attr| attr.oid == ID_MESSAGE_DIGEST)
+        .and_then(|attr| attr.values.iter().next())
+        .ok_or_else(|| malformed_signature("no message digest attribute"))?
+        .decode_as::<OctetString>()
+        .map_err(malformed_signature)?;
+    if message_digest.as_bytes() != Sha256::digest(parsed.content()).as_slice() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Digest mismatch: signed attributes do not match the signed content",
+        ));
+    }
+
+    let signed_attrs = signed_attrs.to_der().map_err(malformed_signature)?;
+    if !verify_rsa(
+        &public_key(signer)?,
+        signer_info.signature_algorithm.oid,
+        &signed_attrs,
+        signer_info.signature.as_bytes(),
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Signature does not match signer certificate {}", signer.tbs_certificate.subject),
+        ));
+    }
+
+    let certificates = parsed.certificates();
+    let mut current = signer;
+    for _ in 0..MAX_CHAIN_LENGTH {
+        if trust.is_trusted(current) {
+            return Ok(signer.tbs_certificate.subject.to_string());
+        }
+
+        match certificates.iter().find(|c| **c != current && issued_by(current, c)) {
+            Some(issuer) => current = issuer,
+            None => break,
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Unknown signer: {} is not trusted", signer.tbs_certificate.subject),
+    ))
+}
+
+/// Formats bytes as lowercase hexadecimal
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reads an X.509 certificate in either PEM or DER form
+pub fn load_certificate(path: &Path) -> io::Result<Certificate> {
+    let bytes = fs::read(path)?;
+    let certificate = if bytes.starts_with(b"-----BEGIN") {
+        Certificate::from_pem(&bytes)
+    } else {
+        Certificate::from_der(&bytes)
+    };
+    certificate.map_err(|err| invalid_data(&format!("Failed to parse certificate {}", path.display()), err))
+}
+
+/// Reads an RSA private key in PKCS#1 or PKCS#8 form, either PEM or DER encoded
+fn load_private_key(path: &Path) -> io::Result<RsaPrivateKey> {
+    let bytes = fs::read(path)?;
+    let key = match std::str::from_utf8(&bytes) {
+        Ok(pem) if pem.starts_with("-----BEGIN") => RsaPrivateKey::from_pkcs8_pem(pem)
+            .ok()
+            .or_else(|| RsaPrivateKey::from_pkcs1_pem(pem).ok()),
+        _ => RsaPrivateKey::from_pkcs8_der(&bytes)
+            .ok()
+            .or_else(|| RsaPrivateKey::from_pkcs1_der(&bytes).ok()),
+    };
+    key.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to parse RSA key {}", path.display()),
+        )
+    })
+}
+
+/// A private key and its certificate, used to produce Authenticode signatures
+pub struct Signer {
+    key: RsaPrivateKey,
+    certificate: Certificate,
+}
+
+impl Signer {
+    pub fn load(key_path: &Path, certificate_path: &Path) -> io::Result<Signer> {
+        let key = load_private_key(key_path)?;
+        let certificate = load_certificate(certificate_path)?;
+
+        if public_key(&certificate)? != RsaPublicKey::from(&key) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Key {} does not match certificate {}",
+                    key_path.display(),
+                    certificate_path.display()
+                ),
+            ));
+        }
+
+        Ok(Signer { key, certificate })
+    }
+
+    /// Returns a trust store holding just the signing certificate, to check images signed with it
+    pub fn trust_store(&self) -> TrustStore {
+        TrustStore {
+            certificates: vec![self.certificate.clone()],
+            digests: Vec::new(),
+        }
+    }
+
+    /// Creates a DER-encoded PKCS#7 SignedData structure over the given Authenticode image digest
+    pub fn sign(&self, image_digest: &[u8]) -> io::Result<Vec<u8>> {
+        self.build_signed_data(image_digest)
+            .map_err(|err| io::Error::other(format!("Failed to sign image: {}", err)))
+    }
+
+    fn build_signed_data(&self, image_digest: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let content = SpcIndirectDataContent {
+            data: SpcAttributeTypeAndOptionalValue {
+                value_type: SPC_PE_IMAGE_DATA_OBJID,
+                value: Any::from_der(SPC_PE_IMAGE_DATA)?,
+            },
+            message_digest: DigestInfo {
+                digest_algorithm: sha256_algorithm(),
+                digest: OctetString::new(image_digest)?,
+            },
+        };
+        let content = Any::encode_from(&content)?;
+
+        // Authenticode signs the content octets of SpcIndirectDataContent, without its tag and length.
+        let content_digest = Sha256::digest(content.value());
+
+        let signed_attrs = SetOfVec::try_from(vec![
+            attribute(ID_CONTENT_TYPE, Any::encode_from(&SPC_INDIRECT_DATA_OBJID)?)?,
+            attribute(ID_MESSAGE_DIGEST, Any::encode_from(&OctetString::new(content_digest.as_slice())?)?)?,
+            attribute(SPC_SP_OPUS_INFO_OBJID, Any::from_der(&[0x30, 0x00])?)?,
+        ])?;
+
+        let signature = self
+            .key
+            .sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(signed_attrs.to_der()?))?;
+
+        let tbs = &self.certificate.tbs_certificate;
+        let signer_info = SignerInfo {
+            version: CmsVersion::V1,
+            sid: SignerIdentifier::IssuerAndSerialNumber(IssuerAndSerialNumber {
+                issuer: tbs.issuer.clone(),
+                serial_number: tbs.serial_number.clone(),
+            }),
+            digest_alg: sha256_algorithm(),
+            signed_attrs: Some(signed_attrs),
+            signature_algorithm: AlgorithmIdentifierOwned {
+                oid: RSA_ENCRYPTION,
+                parameters: Some(Any::null()),
+            },
+            signature: OctetString::new(signature)?,
+            unsigned_attrs: None,
+        };
+
+        let signed_data = SignedData {
+            version: CmsVersion::V1,
+            digest_algorithms: SetOfVec::try_from(vec![sha256_algorithm()])?,
+            encap_content_info: EncapsulatedContentInfo {
+                econtent_type: SPC_INDIRECT_DATA_OBJID,
+                econtent: Some(content),
+            },
+            certificates: Some(
+                vec![CertificateChoices::Certificate(self.certificate.clone())].try_into()?,
+            ),
+            crls: None,
+            signer_infos: vec![signer_info].try_into()?,
+        };
+
+        let content_info = ContentInfo {
+            content_type: ID_SIGNED_DATA,
+            content: Any::encode_from(&signed_data)?,
+        };
+
+        Ok(content_info.to_der()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    fn test_signer() -> Signer {
+        Signer::load(&testdata("test.key"), &testdata("test.crt")).unwrap()
+    }
+
+    #[test]
+    fn sign_embeds_image_digest() {
+        let signer = test_signer();
+        let digest = Sha256::digest(b"image").to_vec();
+        let signature = signer.sign(&digest).unwrap();
+
+        let content_info = ContentInfo::from_der(&signature).unwrap();
+        assert_eq!(content_info.content_type, ID_SIGNED_DATA);
+        let signed_data: SignedData = content_info.content.decode_as().unwrap();
+        assert_eq!(signed_data.encap_content_info.econtent_type, SPC_INDIRECT_DATA_OBJID);
+        assert_eq!(signed_data.signer_infos.0.len(), 1);
+
+        let content: SpcIndirectDataContent = signed_data.encap_content_info.econtent.unwrap().decode_as().unwrap();
+        assert_eq!(content.message_digest.digest_algorithm.oid, ID_SHA256);
+        assert_eq!(content.message_digest.digest.as_bytes(), digest);
+    }
+
+    #[test]
+    fn load_rejects_mismatched_key() {
+        let err = Signer::load(&testdata("other.key"), &testdata("test.crt")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    fn trust(signer: &Signer) -> TrustStore {
+        TrustStore {
+            certificates: vec![signer.certificate.clone()],
+            digests: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let signer = test_signer();
+        let digest = Sha256::digest(b"image");
+        let signature = signer.sign(&digest).unwrap();
+
+        assert_eq!(verify(&signature, &digest, &trust(&signer)).unwrap(), "CN=sigen test");
+    }
+
+    #[test]
+    fn verify_rejects_other_image() {
+        let signer = test_signer();
+        let signature = signer.sign(&Sha256::digest(b"image")).unwrap();
+
+        let err = verify(&signature, &Sha256::digest(b"other image"), &trust(&signer)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn verify_rejects_untrusted_signer() {
+        let signer = test_signer();
+        let digest = Sha256::digest(b"image");
+        let signature = signer.sign(&digest).unwrap();
+
+        let err = verify(&signature, &digest, &TrustStore::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn verify_rejects_tampered_signature() {
+        let signer = test_signer();
+        let digest = Sha256::digest(b"image");
+        let mut signature = signer.sign(&digest).unwrap();
+        let last = signature.len() - 1;
+        signature[last] ^= 1;
+
+        assert!(verify(&signature, &digest, &trust(&signer)).is_err());
+        assert!(verify(b"not a signature", &digest, &trust(&signer)).is_err());
+    }
+
+    #[test]
+    fn describe_signature() {
+        let signer = test_signer();
+        let digest = Sha256::digest(b"image").to_vec();
+        let info = describe(&signer.sign(&digest).unwrap()).unwrap();
+
+        assert_eq!(info.signer, "CN=sigen test");
+        assert_eq!(info.issuer, "CN=sigen test");
+        assert_eq!(info.serial, hex(signer.certificate.tbs_certificate.serial_number.as_bytes()));
+        assert_eq!(info.digest_algorithm, "sha256");
+        assert_eq!(info.digest, digest);
+    }
+
+    #[test]
+    fn describe_rejects_garbage() {
+        assert!(matches!(describe(b"not a signature"), Err(err) if err.kind() == io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cmp::Reverse;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::atomic;
+use crate::authenticode::TrustStore;
+use crate::kernel;
+use crate::pe::PeImage;
+use crate::verify;
+
+/// A previous output kept in the backup directory
+pub struct Backup {
+    pub path: PathBuf,
+    pub modified: SystemTime,
+}
+
+/// Formats a time as `YYYYmmdd-HHMMSS` in UTC
+pub fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, time_of_day) = (seconds / 86400, seconds % 86400);
+
+    // Converts days since the epoch to a proleptic Gregorian date
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    )
+}
+
+/// Returns the release of the kernel embedded in an image, from `.uname` or the kernel's header
+pub fn kernel_release(image: &PeImage) -> Option<String> {
+    match image.find_section(".uname") {
+        Some(uname) => Some(String::from_utf8_lossy(uname).trim_end_matches('\0').to_owned()),
+        None => image.find_section(".linux").and_then(kernel::version),
+    }
+}
+
+fn file_name(output: &Path) -> String {
+    output
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Lists the backups of `output` in `directory`, newest first
+pub fn list(directory: &Path, output: &Path) -> io::Result<Vec<Backup>> {
+    let prefix = format!("{}.", file_name(output));
+    let mut backups = Vec::new();
+
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(&prefix) || !name.ends_with(".bak") || !entry.file_type()?.is_file() {
+            continue;
+        }
+        backups.push(Backup {
+            path: entry.path(),
+            modified: entry.metadata()?.modified()?,
+        });
+    }
+
+    backups.sort_by_key(|backup| Reverse(backup.modified));
+    Ok(backups)
+}
+
+/// Checks that a backup is a complete image, and that it is trusted if signing is in use
+pub fn is_known_good(path: &Path, trust: Option<&TrustStore>) -> bool {
+    let image = match fs::read(path).and_then(PeImage::parse) {
+        Ok(image) => image,
+        Err(_) => return false,
+    };
+
+    image.find_section(".linux").is_some()
+        && trust.is_none_or(|trust| verify::check_image(&image, path, trust).is_ok())
+}
+
+/// Returns the path the current output would be backed up to
+pub fn path(output: &Path, directory: &Path) -> io::Result<PathBuf> {
+    let release = PeImage::parse(fs::read(output)?).ok().and_then(|image| kernel_release(&image));
+
+    // Rebuilds of the same kernel are common, so the time makes each backup's name unique
+    let mut tag = timestamp(SystemTime::now());
+    if let Some(release) = release {
+        tag = format!("{}.{}", release.replace('/', "_"), tag);
+    }
+
+    let mut path = directory.join(format!("{}.{}.bak", file_name(output), tag));
+    let mut counter = 1;
+    while path.exists() {
+        counter += 1;
+        path = directory.join(format!("{}.{}.{}.bak", file_name(output), tag, counter));
+    }
+    Ok(path)
+}
+
+/// Copies the current output to `path` in the backup directory, then removes all but the newest
+/// `keep` backups
+pub fn store(output: &Path, path: &Path, keep: usize, trust: Option<&TrustStore>) -> io::Result<()> {
+    let directory = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(directory)?;
+
+    // Backups are never replaced, so that a known-good image can't be lost before pruning decides
+    if path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Backup {} already exists", path.display()),
+        ));
+    }
+    let data = fs::read(output)?;
+
+    print!("Backing up {} to {}...", output.display(), path.display());
+    io::stdout().flush()?;
+    atomic::write(path, &data)?;
+    println!(" done");
+
+    let backups = list(directory, output)?;
+    if backups.len() <= keep {
+        return Ok(());
+    }
+    let (kept, old) = backups.split_at(keep);
+
+    // Never prune the last image that is known to boot, even if it is older than the rest
+    let mut protected = None;
+    if !kept.iter().any(|backup| is_known_good(&backup.path, trust)) {
+        protected = old.iter().position(|backup| is_known_good(&backup.path, trust));
+        if let Some(index) = protected {
+            eprintln!(
+                "Warning: None of the backups being kept is known to be good, also keeping {}",
+                old[index].path.display()
+            );
+        }
+    }
+
+    for (index, backup) in old.iter().enumerate() {
+        if Some(index) != protected {
+            println!("Removing old backup {}", backup.path.display());
+            fs::remove_file(&backup.path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::time::Duration;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn write_backup(path: &Path, data: &[u8], modified: SystemTime) {
+        fs::write(path, data).unwrap();
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    fn names(directory: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn timestamp_at_known_times() {
+        assert_eq!(timestamp(at(0)), "19700101-000000");
+        assert_eq!(timestamp(at(951782400)), "20000229-000000");
+        assert_eq!(timestamp(at(1709164799)), "20240228-235959");
+        assert_eq!(timestamp(at(1709209845)), "20240229-123045");
+        assert_eq!(timestamp(at(4107542400)), "21000301-000000");
+    }
+
+    #[test]
+    fn store_keeps_newest_backups() {
+        let directory = tempfile::tempdir().unwrap();
+        let output = directory.path().join("linux.efi");
+        fs::write(&output, b"current").unwrap();
+        let backups = directory.path().join("backups");
+        fs::create_dir(&backups).unwrap();
+        for (name, modified) in [("a", 1000), ("b", 3000), ("c", 2000)] {
+            write_backup(&backups.join(format!("linux.efi.{}.bak", name)), b"old", at(modified));
+        }
+        fs::write(backups.join("other.efi.d.bak"), b"other").unwrap();
+
+        store(&output, &backups.join("linux.efi.new.bak"), 2, None).unwrap();
+        assert_eq!(names(&backups), ["linux.efi.b.bak", "linux.efi.new.bak", "other.efi.d.bak"]);
+        assert_eq!(fs::read(backups.join("linux.efi.new.bak")).unwrap(), b"current");
+
+        let err = store(&output, &backups.join("linux.efi.new.bak"), 2, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn store_keeps_last_known_good_backup() {
+        let mut image = PeImage::parse(crate::pe::tests::test_image()).unwrap();
+        image.add_section(".linux", b"kernel").unwrap();
+
+        let directory = tempfile::tempdir().unwrap();
+        let output = directory.path().join("linux.efi");
+        fs::write(&output, b"broken").unwrap();
+        let backups = directory.path().join("backups");
+        fs::create_dir(&backups).unwrap();
+        write_backup(&backups.join("linux.efi.a.bak"), &image.into_bytes(), at(1000));
+        write_backup(&backups.join("linux.efi.b.bak"), b"broken", at(2000));
+        write_backup(&backups.join("linux.efi.c.bak"), b"broken", at(3000));
+
+        store(&output, &backups.join("linux.efi.new.bak"), 1, None).unwrap();
+        assert_eq!(names(&backups), ["linux.efi.a.bak", "linux.efi.new.bak"]);
+    }
+}
@@ -0,0 +1,319 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+
+use crate::disk;
+
+/// Parameters that the boot loader adds at boot time, and that make no sense in an image
+const BOOT_TIME_PARAMS: &[&str] = &["BOOT_IMAGE", "initrd"];
+
+/// Parameters of which only one value takes effect, so repeating them is most likely a mistake
+const SINGLE_VALUE_PARAMS: &[&str] = &[
+    "root",
+    "rootfstype",
+    "rootflags",
+    "init",
+    "resume",
+    "resume_offset",
+    "systemd.unit",
+];
+
+/// Parameters that drop into a root shell, which defeats the purpose of a signed image
+const DEBUG_SHELL_PARAMS: &[&str] = &["rd.break", "rd.shell", "systemd.debug_shell"];
+
+/// Programs that give a root shell when used as `init=`
+const SHELLS: &[&str] = &["sh", "bash", "dash", "zsh", "ash", "busybox"];
+
+/// A problem found while checking a command line
+pub struct Lint {
+    /// Whether the command line must not be embedded as is
+    pub fatal: bool,
+    pub message: String,
+}
+
+/// A kernel command line, split into individual parameters
+pub struct Cmdline {
+    params: Vec<String>,
+}
+
+/// Returns the name of a parameter, i.e. everything before the first `=` outside of quotes
+fn param_name(param: &str) -> &str {
+    let mut in_quote = false;
+    for (i, c) in param.char_indices() {
+        match c {
+            '"' => in_quote = !in_quote,
+            '=' if !in_quote => return &param[..i],
+            _ => {}
+        }
+    }
+    param
+}
+
+/// Returns the value of a parameter without surrounding quotes, or `None` if it has no value
+fn param_value(param: &str) -> Option<&str> {
+    let value = param.get(param_name(param).len() + 1..)?;
+    Some(value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value))
+}
+
+/// Splits a command line the way the kernel does: on whitespace, except inside double quotes
+fn split(text: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    let mut current = String::new();
+    let mut in_quote = false;
+
+    for c in text.chars() {
+        match c {
+            '"' => {
+                in_quote = !in_quote;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !in_quote => {
+                if !current.is_empty() {
+                    params.push(std::mem::take(&mut current));
+                }
+            }
+            '\0' => break,
+            c => current.push(c),
+        }
+    }
+
+    if !current.is_empty() {
+        params.push(current);
+    }
+    params
+}
+
+impl Cmdline {
+    /// Index where kernel parameters end, as everything after `--` is passed to init
+    fn kernel_params_end(&self) -> usize {
+        self.params.iter().position(|param| param == "--").unwrap_or(self.params.len())
+    }
+
+    pub fn parse(text: &str) -> Cmdline {
+        Cmdline { params: split(text) }
+    }
+
+    /// Reads the running system's command line, without the parameters added by the boot loader
+    pub fn from_proc() -> io::Result<Cmdline> {
+        let mut cmdline = Cmdline::parse(&fs::read_to_string("/proc/cmdline")?);
+        for name in BOOT_TIME_PARAMS {
+            cmdline.remove(name);
+        }
+        Ok(cmdline)
+    }
+
+    /// Replaces every occurrence of a parameter with a single `param` after the other kernel parameters
+    pub fn set(&mut self, param: &str) {
+        self.remove(param_name(param));
+        let end = self.kernel_params_end();
+        self.params.insert(end, param.to_owned());
+    }
+
+    /// Adds the parameters in `text` after the other kernel parameters
+    pub fn append(&mut self, text: &str) {
+        let end = self.kernel_params_end();
+        self.params.splice(end..end, split(text));
+    }
+
+    /// Removes every kernel parameter called `name`, or exactly matching `name` if it contains a value
+    pub fn remove(&mut self, name: &str) {
+        let init_params = self.params.split_off(self.kernel_params_end());
+        if name.contains('=') {
+            self.params.retain(|param| param != name);
+        } else {
+            self.params.retain(|param| param_name(param) != name);
+        }
+        self.params.extend(init_params);
+    }
+
+    /// Looks for mistakes that would only show up at boot, and for debug shells if the image is signed
+    pub fn lint(&self, signing: bool) -> Vec<Lint> {
+        let mut lints = Vec::new();
+        let kernel_params = &self.params[..self.kernel_params_end()];
+
+        // A stray quote makes the kernel swallow everything after it into a single parameter
+        for param in &self.params {
+            if param.matches('"').count() % 2 != 0 {
+                lints.push(Lint {
+                    fatal: true,
+                    message: format!("Unbalanced quote in {}", param),
+                });
+            }
+        }
+
+        for name in SINGLE_VALUE_PARAMS {
+            let values: Vec<_> = kernel_params.iter().filter(|param| param_name(param) == *name).collect();
+            if values.len() < 2 {
+                continue;
+            }
+            let conflicting = values.iter().any(|value| value != &values[0]);
+            lints.push(Lint {
+                fatal: conflicting,
+                message: format!(
+                    "{} is given {} times{}",
+                    name,
+                    values.len(),
+                    if conflicting { " with different values" } else { "" }
+                ),
+            });
+        }
+
+        for param in kernel_params {
+            let name = param_name(param);
+            let value = param_value(param);
+
+            if name == "root" || name == "resume" {
+                let device = value.and_then(|value| {
+                    value
+                        .strip_prefix("UUID=")
+                        .map(|id| ("uuid", id))
+                        .or_else(|| value.strip_prefix("PARTUUID=").map(|id| ("partuuid", id)))
+                        .or_else(|| value.strip_prefix("/dev/disk/by-uuid/").map(|id| ("uuid", id)))
+                        .or_else(|| value.strip_prefix("/dev/disk/by-partuuid/").map(|id| ("partuuid", id)))
+                });
+                if let Some((kind, id)) = device {
+                    // Only a warning, as the image may be meant for another machine or a chroot
+                    if disk::link_exists(kind, id) == Some(false) {
+                        lints.push(Lint {
+                            fatal: false,
+                            message: format!("{} refers to a device that is not in /dev/disk/by-{}", param, kind),
+                        });
+                    }
+                }
+            }
+
+            let debug_shell = match (name, value) {
+                ("init", Some(init)) => SHELLS.contains(&init.rsplit('/').next().unwrap_or(init)),
+                (name, value) if DEBUG_SHELL_PARAMS.contains(&name) => !matches!(value, Some("0" | "no" | "false")),
+                _ => false,
+            };
+            if debug_shell {
+                lints.push(Lint {
+                    fatal: signing,
+                    message: format!("{} gives a root shell at boot", param),
+                });
+            }
+        }
+
+        lints
+    }
+
+    /// Returns the normalised command line, NUL-terminated as systemd-stub expects
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.params.join(" ").into_bytes();
+        bytes.push(0);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(cmdline: &Cmdline) -> String {
+        String::from_utf8(cmdline.to_bytes()).unwrap().trim_end_matches('\0').to_owned()
+    }
+
+    #[test]
+    fn split_on_whitespace_outside_quotes() {
+        assert_eq!(split("  root=/dev/sda2\tquiet\n"), ["root=/dev/sda2", "quiet"]);
+        assert_eq!(
+            split("dyndbg=\"file foo.c +p\" \"quoted param\"=x rw"),
+            ["dyndbg=\"file foo.c +p\"", "\"quoted param\"=x", "rw"]
+        );
+        assert_eq!(split("quiet\0ignored"), ["quiet"]);
+        assert!(split("").is_empty());
+    }
+
+    #[test]
+    fn param_names_and_values() {
+        assert_eq!(param_name("root=/dev/sda2"), "root");
+        assert_eq!(param_name("\"a=b\"=c"), "\"a=b\"");
+        assert_eq!(param_name("quiet"), "quiet");
+        assert_eq!(param_value("dyndbg=\"file foo.c +p\""), Some("file foo.c +p"));
+        assert_eq!(param_value("root="), Some(""));
+        assert_eq!(param_value("quiet"), None);
+    }
+
+    #[test]
+    fn append_and_remove() {
+        let mut cmdline = Cmdline::parse("root=/dev/sda1 quiet loglevel=3");
+        cmdline.append("splash loglevel=4");
+        cmdline.remove("loglevel=3");
+        assert_eq!(text(&cmdline), "root=/dev/sda1 quiet splash loglevel=4");
+
+        cmdline.remove("loglevel");
+        cmdline.remove("quiet");
+        assert_eq!(text(&cmdline), "root=/dev/sda1 splash");
+        assert_eq!(cmdline.to_bytes().last(), Some(&0));
+    }
+
+    #[test]
+    fn edit_before_init_params() {
+        let mut cmdline = Cmdline::parse("root=/dev/sda1 quiet -- single root=init");
+        cmdline.set("root=/dev/sda2");
+        cmdline.append("splash loglevel=3");
+        cmdline.remove("quiet");
+        assert_eq!(text(&cmdline), "root=/dev/sda2 splash loglevel=3 -- single root=init");
+
+        cmdline.append("quiet loglevel=4");
+        cmdline.remove("loglevel=3");
+        assert_eq!(text(&cmdline), "root=/dev/sda2 splash quiet loglevel=4 -- single root=init");
+    }
+
+    fn lints(text: &str, signing: bool) -> Vec<(bool, String)> {
+        Cmdline::parse(text)
+            .lint(signing)
+            .into_iter()
+            .map(|lint| (lint.fatal, lint.message))
+            .collect()
+    }
+
+    #[test]
+    fn lint_repeated_params() {
+        assert_eq!(lints("quiet root=/dev/sda2 root=/dev/sda2", false), [(false, "root is given 2 times".to_owned())]);
+        assert_eq!(
+            lints("root=/dev/sda1 root=/dev/sda2", false),
+            [(true, "root is given 2 times with different values".to_owned())]
+        );
+        assert!(lints("root=/dev/sda2 -- root=x root=y", false).is_empty());
+    }
+
+    #[test]
+    fn lint_debug_shells() {
+        assert!(lints("init=/bin/bash", false).iter().all(|(fatal, _)| !fatal));
+        assert_eq!(lints("init=/bin/bash", true), [(true, "init=/bin/bash gives a root shell at boot".to_owned())]);
+        assert_eq!(lints("rd.shell", true).len(), 1);
+        assert!(lints("rd.shell=0 systemd.debug_shell=no init=/usr/lib/systemd/systemd", true).is_empty());
+    }
+
+    #[test]
+    fn lint_unbalanced_quotes() {
+        assert_eq!(lints("dyndbg=\"file foo.c", false), [(true, "Unbalanced quote in dyndbg=\"file foo.c".to_owned())]);
+    }
+
+    #[test]
+    fn lint_allows_repeated_luks_names() {
+        assert!(lints("rd.luks.name=a=root rd.luks.name=b=home", false).is_empty());
+    }
+
+    #[test]
+    fn lint_missing_uuid_is_not_fatal() {
+        let lints = lints("root=UUID=00000000-0000-0000-0000-000000000000 resume=PARTUUID=0", true);
+        assert!(lints.iter().all(|(fatal, _)| !fatal));
+    }
+}
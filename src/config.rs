@@ -0,0 +1,82 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+pub const DEFAULT_PATH: &str = "/etc/sigen.toml";
+
+/// The contents of the configuration file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A named set of build options, each one overridable from the command line
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub kernel: Option<PathBuf>,
+    #[serde(default)]
+    pub initrd: Vec<PathBuf>,
+    pub cmdline: Option<PathBuf>,
+    pub cmdline_string: Option<String>,
+    #[serde(default)]
+    pub cmdline_from_proc: bool,
+    pub stub: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub backup: Option<PathBuf>,
+    pub backup_dir: Option<PathBuf>,
+    pub keep: Option<usize>,
+    /// Paths to the .key and .crt files, in this order
+    pub sign: Option<[PathBuf; 2]>,
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Failed to read configuration file {}: {}", path.display(), err),
+            )
+        })?;
+
+        toml::from_str(&contents).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid configuration file {}: {}", path.display(), err),
+            )
+        })
+    }
+
+    /// Removes and returns the profile called `name`
+    pub fn take_profile(&mut self, name: &str) -> io::Result<Profile> {
+        self.profiles.remove(name).ok_or_else(|| {
+            let available = self.profiles.keys().map(String::as_str).collect::<Vec<_>>().join(", ");
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No profile called {} (available profiles: {})", name, available),
+            )
+        })
+    }
+}
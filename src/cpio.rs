@@ -0,0 +1,318 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// A file to add to the initramfs, given as `<src>:<dest>[:mode]`
+#[derive(Clone)]
+pub struct FileSpec {
+    source: PathBuf,
+    destination: String,
+    mode: Option<u32>,
+}
+
+impl FromStr for FileSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<FileSpec, String> {
+        let mut parts = spec.splitn(3, ':');
+        let (source, destination) = match (parts.next(), parts.next()) {
+            (Some(source), Some(destination)) if !source.is_empty() && !destination.is_empty() => {
+                (source, destination)
+            }
+            _ => return Err(format!("Invalid file {:?}, expected <src>:<dest>[:mode]", spec)),
+        };
+        let mode = match parts.next() {
+            Some(mode) => match u32::from_str_radix(mode, 8) {
+                Ok(mode) if mode <= 0o7777 => Some(mode),
+                _ => return Err(format!("Invalid mode {:?}, expected an octal number such as 0600", mode)),
+            },
+            None => None,
+        };
+
+        Ok(FileSpec {
+            source: PathBuf::from(source),
+            destination: destination.to_owned(),
+            mode,
+        })
+    }
+}
+
+/// Builds an uncompressed newc cpio archive, the format the kernel unpacks into the initramfs
+pub struct Archive {
+    data: Vec<u8>,
+    next_inode: u32,
+    directories: HashSet<String>,
+}
+
+impl Archive {
+    pub fn new() -> Archive {
+        Archive {
+            data: Vec::new(),
+            next_inode: 1,
+            directories: HashSet::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.next_inode == 1
+    }
+
+    fn add_entry(&mut self, name: &str, mode: u32, contents: &[u8]) -> io::Result<()> {
+        let size = u32::try_from(contents.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is too large for cpio", name)))?;
+        let nlink = if mode & S_IFDIR != 0 { 2 } else { 1 };
+
+        // Timestamps and owners are left at zero, so that the same input always gives the same archive
+        let fields = [self.next_inode, mode, 0, 0, nlink, 0, size, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        self.next_inode += 1;
+
+        self.data.extend_from_slice(b"070701");
+        for field in fields {
+            self.data.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.pad();
+        self.data.extend_from_slice(contents);
+        self.pad();
+
+        Ok(())
+    }
+
+    fn pad(&mut self) {
+        self.data.resize(self.data.len().next_multiple_of(4), 0);
+    }
+
+    /// Adds the missing parent directories of `name`
+    fn add_parents(&mut self, name: &str) -> io::Result<()> {
+        let mut end = 0;
+        while let Some(position) = name[end..].find('/') {
+            end += position;
+            self.add_directory(&name[..end], 0o755)?;
+            end += 1;
+        }
+        Ok(())
+    }
+
+    fn add_directory(&mut self, name: &str, permissions: u32) -> io::Result<()> {
+        if self.directories.insert(name.to_owned()) {
+            self.add_entry(name, S_IFDIR | permissions, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Adds a file, or a directory tree, from the filesystem as `name`
+    fn add_path(&mut self, source: &Path, name: &str) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(source)?;
+        let permissions = metadata.permissions().mode() & 0o7777;
+        let file_type = metadata.file_type();
+
+        if file_type.is_dir() {
+            self.add_directory(name, permissions)?;
+
+            let mut entries = fs::read_dir(source)?.collect::<io::Result<Vec<_>>>()?;
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let child = format!("{}/{}", name, entry.file_name().to_string_lossy());
+                self.add_path(&entry.path(), &child)?;
+            }
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(source)?;
+            self.add_entry(name, S_IFLNK | 0o777, target.as_os_str().as_bytes())?;
+        } else if file_type.is_file() {
+            self.add_entry(name, S_IFREG | permissions, &fs::read(source)?)?;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a regular file, directory or symlink", source.display()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Adds the contents of a directory to the root of the archive
+    pub fn add_directory_contents(&mut self, source: &Path) -> io::Result<()> {
+        let mut entries = fs::read_dir(source)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            self.add_path(&entry.path(), &entry.file_name().to_string_lossy())?;
+        }
+        Ok(())
+    }
+
+    /// Adds a file at the destination given in `spec`, creating its parent directories
+    pub fn add_file(&mut self, spec: &FileSpec) -> io::Result<()> {
+        let name = spec.destination.trim_start_matches('/');
+        if name.is_empty() || name.split('/').any(|component| component.is_empty() || component == "..") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid initramfs destination {}", spec.destination),
+            ));
+        }
+        if !spec.source.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Failed to find {}", spec.source.display()),
+            ));
+        }
+
+        // Follow symlinks, as their targets would not be in the initramfs
+        let metadata = fs::metadata(&spec.source)?;
+        let permissions = spec.mode.unwrap_or(metadata.permissions().mode() & 0o7777);
+
+        self.add_parents(name)?;
+        self.add_entry(name, S_IFREG | permissions, &fs::read(&spec.source)?)
+    }
+
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        self.next_inode = 0;
+        self.add_entry("TRAILER!!!", 0, &[])?;
+        Ok(self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the name, mode and contents of each entry of a newc archive
+    fn entries(data: &[u8]) -> Vec<(String, u32, Vec<u8>)> {
+        let field = |offset: usize| {
+            u32::from_str_radix(std::str::from_utf8(&data[offset..offset + 8]).unwrap(), 16).unwrap()
+        };
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let mode = field(offset + 14);
+            let size = field(offset + 54) as usize;
+            let name_size = field(offset + 94) as usize;
+            let name = String::from_utf8(data[offset + 110..offset + 110 + name_size - 1].to_vec()).unwrap();
+            let start = (offset + 110 + name_size).next_multiple_of(4);
+            if name == "TRAILER!!!" {
+                return entries;
+            }
+            entries.push((name, mode, data[start..start + size].to_vec()));
+            offset = (start + size).next_multiple_of(4);
+        }
+    }
+
+    #[test]
+    fn parse_file_spec() {
+        let spec: FileSpec = "/etc/key:/etc/keys/root.key:0400".parse().unwrap();
+        assert_eq!(spec.source, Path::new("/etc/key"));
+        assert_eq!(spec.destination, "/etc/keys/root.key");
+        assert_eq!(spec.mode, Some(0o400));
+
+        assert_eq!("a:b".parse::<FileSpec>().unwrap().mode, None);
+        assert!("a".parse::<FileSpec>().is_err());
+        assert!(":b".parse::<FileSpec>().is_err());
+        assert!("a:b:888".parse::<FileSpec>().is_err());
+        assert!("a:b:10000".parse::<FileSpec>().is_err());
+    }
+
+    #[test]
+    fn add_file_with_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("key");
+        fs::write(&source, "secret").unwrap();
+
+        let mut archive = Archive::new();
+        archive.add_file(&format!("{}:/etc/keys/a:0400", source.display()).parse().unwrap()).unwrap();
+        archive.add_file(&format!("{}:/etc/keys/b:0600", source.display()).parse().unwrap()).unwrap();
+        assert!(!archive.is_empty());
+
+        let entries = entries(&archive.finish().unwrap());
+        assert_eq!(
+            entries,
+            [
+                ("etc".to_owned(), S_IFDIR | 0o755, Vec::new()),
+                ("etc/keys".to_owned(), S_IFDIR | 0o755, Vec::new()),
+                ("etc/keys/a".to_owned(), S_IFREG | 0o400, b"secret".to_vec()),
+                ("etc/keys/b".to_owned(), S_IFREG | 0o600, b"secret".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn add_file_rejects_invalid_destinations() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("key");
+        fs::write(&source, "secret").unwrap();
+
+        let mut archive = Archive::new();
+        for destination in ["/", "etc//key", "../key", "etc/../../key"] {
+            let spec = format!("{}:{}", source.display(), destination).parse().unwrap();
+            assert_eq!(archive.add_file(&spec).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+
+        let missing = format!("{}:key", dir.path().join("missing").display()).parse().unwrap();
+        assert_eq!(archive.add_file(&missing).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(archive.is_empty());
+    }
+
+    #[test]
+    fn add_directory_contents_is_sorted() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("etc")).unwrap();
+        fs::write(dir.path().join("etc/b"), "b").unwrap();
+        fs::write(dir.path().join("etc/a"), "a").unwrap();
+        std::os::unix::fs::symlink("a", dir.path().join("etc/c")).unwrap();
+
+        let mut archive = Archive::new();
+        archive.add_directory_contents(dir.path()).unwrap();
+        let names: Vec<(String, u32)> = entries(&archive.finish().unwrap())
+            .into_iter()
+            .map(|(name, mode, _)| (name, mode & 0o170000))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("etc".to_owned(), S_IFDIR),
+                ("etc/a".to_owned(), S_IFREG),
+                ("etc/b".to_owned(), S_IFREG),
+                ("etc/c".to_owned(), S_IFLNK),
+            ]
+        );
+    }
+
+    #[test]
+    fn add_file_follows_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("key");
+        let link = dir.path().join("link");
+        fs::write(&target, "secret").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let mut archive = Archive::new();
+        archive.add_file(&format!("{}:key:0400", link.display()).parse().unwrap()).unwrap();
+        assert_eq!(
+            entries(&archive.finish().unwrap()),
+            [("key".to_owned(), S_IFREG | 0o400, b"secret".to_vec())]
+        );
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::kernel;
+
+const BOOT_DIR: &str = "/boot";
+const MODULES_DIR: &str = "/usr/lib/modules";
+
+/// A kernel installed on the system, with the initramfs built for it
+pub struct Kernel {
+    pub path: PathBuf,
+    /// The part of the file name that tells kernels apart, e.g. `linux-lts` for `/boot/vmlinuz-linux-lts`
+    pub name: String,
+    pub version: String,
+    pub initrd: Option<PathBuf>,
+}
+
+impl Kernel {
+    fn new(path: PathBuf, name: &str, initrds: &[PathBuf]) -> io::Result<Kernel> {
+        let version = kernel::version(&fs::read(&path)?).unwrap_or_else(|| name.to_owned());
+        let mut candidates = initrds.iter().cloned().chain([
+            Path::new(BOOT_DIR).join(format!("initramfs-{}.img", version)),
+            Path::new(BOOT_DIR).join(format!("initrd.img-{}", version)),
+        ]);
+
+        Ok(Kernel {
+            initrd: candidates.find(|path| path.is_file()),
+            path,
+            name: name.to_owned(),
+            version,
+        })
+    }
+}
+
+/// Lists the entries of a directory, or nothing if it doesn't exist
+fn read_dir_sorted(directory: &str) -> io::Result<Vec<fs::DirEntry>> {
+    let mut entries = match fs::read_dir(directory) {
+        Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+    Ok(entries)
+}
+
+/// Finds the kernels in /boot and /usr/lib/modules, and pairs each one with its initramfs
+pub fn find_kernels() -> io::Result<Vec<Kernel>> {
+    let mut kernels: Vec<Kernel> = Vec::new();
+
+    for entry in read_dir_sorted(BOOT_DIR)? {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(name) = file_name.strip_prefix("vmlinuz-").filter(|_| entry.path().is_file()) {
+            let boot = Path::new(BOOT_DIR);
+            let initrds = [
+                boot.join(format!("initramfs-{}.img", name)),
+                boot.join(format!("initrd.img-{}", name)),
+            ];
+            kernels.push(Kernel::new(entry.path(), name, &initrds)?);
+        }
+    }
+
+    for entry in read_dir_sorted(MODULES_DIR)? {
+        let path = entry.path().join("vmlinuz");
+        if !path.is_file() {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let kernel = Kernel::new(path, &name, &[entry.path().join("initrd")])?;
+
+        // Most distributions install the same kernel in both places, and the copy in /boot is the
+        // one that the initramfs is named after
+        match kernels.iter_mut().find(|other| other.version == kernel.version) {
+            Some(other) if other.initrd.is_none() && kernel.initrd.is_some() => *other = kernel,
+            Some(_) => {}
+            None => kernels.push(kernel),
+        }
+    }
+
+    Ok(kernels)
+}
+
+/// Replaces the `{variable}`s in `template`, failing on unknown variables or ones without a value
+pub fn expand(template: &str, variables: &[(&str, Option<String>)]) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated variable in output template {}", template))?;
+        let name = &rest[start + 1..start + end];
+
+        let value = match variables.iter().find(|(variable, _)| *variable == name) {
+            Some((_, Some(value))) => value,
+            Some((_, None)) => return Err(format!("No value for {{{}}} in output template", name)),
+            None => {
+                let known: Vec<_> = variables.iter().map(|(variable, _)| format!("{{{}}}", variable)).collect();
+                return Err(format!(
+                    "Unknown variable {{{}}} in output template, expected one of {}",
+                    name,
+                    known.join(", ")
+                ));
+            }
+        };
+
+        // Values come from files, so keep them from adding directories to the path
+        expanded.push_str(&value.replace('/', "_"));
+        rest = &rest[start + end + 1..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("kver", Some("6.6.0-arch1-1".to_owned())),
+            ("id", Some("arch/custom".to_owned())),
+            ("version_id", None),
+        ]
+    }
+
+    #[test]
+    fn expand_replaces_variables() {
+        assert_eq!(
+            expand("/efi/EFI/Linux/{id}-{kver}.efi", &variables()).unwrap(),
+            "/efi/EFI/Linux/arch_custom-6.6.0-arch1-1.efi"
+        );
+        assert_eq!(expand("/efi/linux.efi", &variables()).unwrap(), "/efi/linux.efi");
+    }
+
+    #[test]
+    fn expand_rejects_bad_templates() {
+        assert_eq!(
+            expand("{kver}-{release}.efi", &variables()).unwrap_err(),
+            "Unknown variable {release} in output template, expected one of {kver}, {id}, {version_id}"
+        );
+        assert_eq!(
+            expand("{kver}-{version_id}.efi", &variables()).unwrap_err(),
+            "No value for {version_id} in output template"
+        );
+        assert_eq!(
+            expand("{kver.efi", &variables()).unwrap_err(),
+            "Unterminated variable in output template {kver.efi"
+        );
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use rustix::fs::statvfs;
+
+/// Splits a device number into its major and minor parts, as glibc encodes them
+fn major_minor(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major, minor)
+}
+
+/// Returns the device number of the block device mounted at `/`
+fn root_device() -> io::Result<(u64, u64)> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+
+    // Later mounts on top of / shadow earlier ones, so the last entry wins.
+    let entry = mountinfo
+        .lines()
+        .rev()
+        .map(|line| line.split(' ').collect::<Vec<_>>())
+        .find(|fields| fields.len() > 4 && fields[4] == "/")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Failed to find the root mount"))?;
+
+    // Filesystems such as btrfs report an anonymous device number, so prefer the mount source.
+    let source = entry
+        .iter()
+        .position(|&field| field == "-")
+        .and_then(|separator| entry.get(separator + 2));
+    if let Some(source) = source.filter(|source| source.starts_with("/dev/")) {
+        if let Ok(metadata) = fs::metadata(source) {
+            if metadata.file_type().is_block_device() {
+                return Ok(major_minor(metadata.rdev()));
+            }
+        }
+    }
+
+    let parse = |number: &str| number.parse::<u64>().ok();
+    entry[2]
+        .split_once(':')
+        .and_then(|(major, minor)| Some((parse(major)?, parse(minor)?)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed /proc/self/mountinfo"))
+}
+
+/// Looks up the name of the `/dev/disk/by-<kind>` link that points to the given device
+fn find_link(kind: &str, device: (u64, u64)) -> io::Result<Option<String>> {
+    let directory = Path::new("/dev/disk").join(format!("by-{}", kind));
+    if !directory.is_dir() {
+        return Ok(None);
+    }
+
+    for entry in fs::read_dir(&directory)? {
+        let entry = entry?;
+        if let Ok(metadata) = fs::metadata(entry.path()) {
+            if metadata.file_type().is_block_device() && major_minor(metadata.rdev()) == device {
+                return Ok(Some(entry.file_name().to_string_lossy().into_owned()));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Checks whether `/dev/disk/by-<kind>` has a link called `id`, or `None` if the directory does not exist
+pub fn link_exists(kind: &str, id: &str) -> Option<bool> {
+    let entries = fs::read_dir(Path::new("/dev/disk").join(format!("by-{}", kind))).ok()?;
+
+    // Filesystem UUIDs are matched case-insensitively at boot
+    Some(entries.filter_map(Result::ok).any(|entry| {
+        entry.file_name().to_string_lossy().eq_ignore_ascii_case(id)
+    }))
+}
+
+/// Builds a `root=` parameter that identifies the current root filesystem by `uuid` or `partuuid`
+pub fn root_parameter(kind: &str) -> io::Result<String> {
+    let device = root_device()?;
+
+    match find_link(kind, device)? {
+        Some(id) => Ok(format!("root={}={}", kind.to_uppercase(), id)),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Failed to find the {} of the root device {}:{} in /dev/disk/by-{}",
+                kind.to_uppercase(),
+                device.0,
+                device.1,
+                kind
+            ),
+        )),
+    }
+}
+
+/// Returns the closest existing directory that `path` would be created in
+fn existing_parent(path: &Path) -> &Path {
+    path.ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.is_dir())
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+fn mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// Checks that the given files, as `(path, size)` pairs, fit in the free space of the filesystems
+/// they are going to be written to
+pub fn check_free_space(files: &[(PathBuf, u64)]) -> io::Result<()> {
+    // Files on the same filesystem share its free space, so add them up per device
+    let mut filesystems: Vec<(u64, &Path, u64, Vec<&Path>)> = Vec::new();
+
+    for (path, size) in files {
+        let directory = existing_parent(path);
+        let device = fs::metadata(directory)?.dev();
+        let stats = statvfs(directory).map_err(io::Error::from)?;
+
+        // Space is allocated in whole blocks
+        let block_size = stats.f_frsize.max(1);
+        let needed = size.div_ceil(block_size) * block_size;
+
+        match filesystems.iter_mut().find(|(other, ..)| *other == device) {
+            Some((_, _, total, paths)) => {
+                *total += needed;
+                paths.push(path);
+            }
+            None => filesystems.push((device, directory, needed, vec![path])),
+        }
+    }
+
+    for (_, directory, needed, paths) in filesystems {
+        let stats = statvfs(directory).map_err(io::Error::from)?;
+        let available = stats.f_bavail * stats.f_frsize;
+        if needed > available {
+            let paths: Vec<_> = paths.iter().map(|path| path.display().to_string()).collect();
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "Not enough space on the filesystem holding {}: writing {} needs {} ({} bytes), but only {} ({} bytes) is free",
+                    directory.display(),
+                    paths.join(" and "),
+                    mib(needed),
+                    needed,
+                    mib(available),
+                    available
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
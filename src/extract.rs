@@ -0,0 +1,114 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use crate::pe::PeImage;
+
+/// Sections that unified kernel images add to the stub
+const UKI_SECTIONS: &[&str] = &[
+    ".osrel", ".cmdline", ".dtb", ".uname", ".splash", ".sbat", ".pcrsig", ".pcrpkey", ".initrd", ".linux",
+];
+
+/// Writes the sections embedded in an EFI executable to a directory
+#[derive(Clone, StructOpt)]
+pub struct ExtractArgs {
+    /// Path to the EFI executable to unpack
+    image: PathBuf,
+    /// Directory to write the sections to
+    #[structopt(short, long, default_value = ".")]
+    directory: PathBuf,
+    /// Name of a section to extract (defaults to all sections added to the stub)
+    #[structopt(short, long)]
+    section: Vec<String>,
+    /// Extract every section, including the stub's own code and data
+    #[structopt(short, long, conflicts_with = "section")]
+    all: bool,
+    /// Overwrite files that already exist in the directory
+    #[structopt(short = "f", long = "force")]
+    overwrite: bool,
+}
+
+pub fn extract(args: ExtractArgs) -> io::Result<()> {
+    let image = PeImage::parse(fs::read(&args.image)?)?;
+
+    for name in &args.section {
+        if image.find_section(name).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no {} section", args.image.display(), name),
+            ));
+        }
+    }
+
+    fs::create_dir_all(&args.directory)?;
+
+    for section in image.sections() {
+        let wanted = if args.all {
+            true
+        } else if args.section.is_empty() {
+            UKI_SECTIONS.contains(&section.name.as_str())
+        } else {
+            args.section.contains(&section.name)
+        };
+        if !wanted {
+            continue;
+        }
+
+        let data = image.section_data(section);
+        if data.is_empty() {
+            println!("Skipping empty section {}", section.name);
+            continue;
+        }
+
+        // Section names come from the image, so keep them from escaping the directory
+        let file_name = match section.name.trim_start_matches('.') {
+            "" => "unnamed".to_owned(),
+            name => name.replace('/', "_"),
+        };
+        let path = args.directory.join(file_name);
+
+        print!("Extracting {} to {}...", section.name, path.display());
+        io::stdout().flush()?;
+
+        let mut options = OpenOptions::new();
+        options.write(true);
+        if args.overwrite {
+            options.create(true).truncate(true);
+        } else {
+            options.create_new(true);
+        }
+
+        match options.open(&path) {
+            Ok(mut file) => file.write_all(data)?,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                println!();
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists, pass -f to overwrite", path.display()),
+                ));
+            }
+            Err(err) => return Err(err),
+        }
+
+        println!(" done ({} bytes)", data.len());
+    }
+
+    Ok(())
+}
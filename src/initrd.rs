@@ -0,0 +1,335 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Boundary each initramfs is placed on, so that every archive starts on its own page
+const ALIGNMENT: usize = 4096;
+
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// Directory the kernel's early loader takes CPU microcode updates from
+const MICROCODE_DIR: &str = "kernel/x86/microcode/";
+
+/// Formats the kernel can unpack from an initramfs, identified by their magic numbers
+const FORMATS: &[(&str, &[u8])] = &[
+    ("cpio", b"070701"),
+    ("cpio", b"070702"),
+    ("gzip", b"\x1f\x8b"),
+    ("gzip", b"\x1f\x9e"),
+    ("zstd", b"\x28\xb5\x2f\xfd"),
+    ("xz", b"\xfd7zXZ\x00"),
+    ("lz4", b"\x02\x21\x4c\x18"),
+    ("lzma", b"\x5d\x00\x00"),
+    ("bzip2", b"BZh"),
+];
+
+fn detect(data: &[u8]) -> Option<&'static str> {
+    FORMATS
+        .iter()
+        .find(|(_, magic)| data.starts_with(magic))
+        .map(|(format, _)| *format)
+}
+
+/// Walks an uncompressed newc cpio archive, collecting the entry names and returning the offset right after its trailer
+fn walk_cpio(data: &[u8], names: &mut Vec<String>) -> Result<usize, String> {
+    let field = |offset: usize| {
+        data.get(offset..offset + 8)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| format!("malformed cpio header at offset {}", offset))
+    };
+
+    let mut offset = 0;
+    loop {
+        if !matches!(data.get(offset..offset + 6), Some(b"070701" | b"070702")) {
+            return Err(format!("missing cpio header at offset {}", offset));
+        }
+
+        let file_size = field(offset + 54)?;
+        let name_size = field(offset + 94)?;
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = data
+            .get(name_start..name_start + name_size)
+            .ok_or("truncated cpio archive")?;
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        offset = (data_start + file_size).next_multiple_of(4);
+
+        if offset > data.len().next_multiple_of(4) {
+            return Err("truncated cpio archive".to_owned());
+        }
+        let name = String::from_utf8_lossy(name.strip_suffix(b"\0").unwrap_or(name));
+        if name == CPIO_TRAILER {
+            return Ok(offset.min(data.len()));
+        }
+        names.push(name.trim_start_matches("./").to_owned());
+    }
+}
+
+/// An initramfs image, checked to be something the kernel can unpack
+pub struct Initrd {
+    pub path: PathBuf,
+    data: Vec<u8>,
+    /// Whether it starts with an uncompressed archive of early microcode updates
+    pub microcode: bool,
+    /// Whether it contains a compressed archive
+    pub compressed: bool,
+}
+
+impl Initrd {
+    /// Reads an initramfs image, making sure the kernel will be able to unpack it
+    pub fn load(path: &Path) -> io::Result<Initrd> {
+        Initrd::parse(path, fs::read(path)?)
+    }
+
+    /// Checks an initramfs image that is already in memory, using `path` to refer to it in messages
+    pub fn parse(path: &Path, data: Vec<u8>) -> io::Result<Initrd> {
+        let (formats, names) = Initrd::validate(&data).map_err(|reason| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid initramfs image {}: {}", path.display(), reason),
+            )
+        })?;
+
+        Ok(Initrd {
+            path: path.to_owned(),
+            microcode: formats[0] == "cpio"
+                && names
+                    .iter()
+                    .any(|name| name.starts_with(MICROCODE_DIR) && name.ends_with(".bin")),
+            compressed: formats.iter().any(|&format| format != "cpio"),
+            data,
+        })
+    }
+
+    /// Checks that `data` consists of archives the kernel can unpack, returning their formats
+    /// and the names of the files in the leading uncompressed ones
+    fn validate(data: &[u8]) -> Result<(Vec<&'static str>, Vec<String>), String> {
+        let mut formats = Vec::new();
+        let mut names = Vec::new();
+        let mut rest = data;
+
+        if rest.is_empty() {
+            return Err("file is empty".to_owned());
+        }
+
+        // Like the kernel, look for further archives after each cpio, skipping the zero padding in between.
+        // Compressed archives can't be followed without decompressing them, so checking stops there.
+        while !rest.is_empty() {
+            let format = detect(rest)
+                .ok_or_else(|| format!("unknown archive format at offset {}", data.len() - rest.len()))?;
+            formats.push(format);
+            if format != "cpio" {
+                break;
+            }
+
+            let end = walk_cpio(rest, &mut names)?;
+            rest = &rest[end..];
+            let padding = rest.iter().position(|&b| b != 0).unwrap_or(rest.len());
+            rest = &rest[padding..];
+        }
+
+        Ok((formats, names))
+    }
+}
+
+/// Moves early microcode images to the front, where the kernel looks for them, returning a
+/// warning for each one that was given after a compressed image
+pub fn move_microcode_first(initrds: &mut [Initrd]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (i, initrd) in initrds.iter().enumerate() {
+        if !initrd.microcode {
+            continue;
+        }
+        if let Some(compressed) = initrds[..i].iter().find(|other| other.compressed && !other.microcode) {
+            warnings.push(format!(
+                "Microcode image {} was given after the compressed image {}, moving it to the front",
+                initrd.path.display(),
+                compressed.path.display()
+            ));
+        }
+    }
+
+    initrds.sort_by_key(|initrd| !initrd.microcode);
+    warnings
+}
+
+/// Finds the distribution's microcode image for the running CPU, such as /boot/amd-ucode.img
+pub fn microcode_image() -> io::Result<Option<PathBuf>> {
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo")?;
+    let vendor = cpuinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("vendor_id"))
+        .and_then(|line| line.split(':').nth(1))
+        .map(str::trim);
+
+    let path = match vendor {
+        Some("AuthenticAMD") => PathBuf::from("/boot/amd-ucode.img"),
+        Some("GenuineIntel") => PathBuf::from("/boot/intel-ucode.img"),
+        _ => return Ok(None),
+    };
+    Ok(Some(path).filter(|path| path.is_file()))
+}
+
+/// Appends an initramfs image to the combined one, starting it on a page boundary
+pub fn append(merged: &mut Vec<u8>, initrd: &Initrd) {
+    merged.resize(merged.len().next_multiple_of(ALIGNMENT), 0);
+    merged.extend_from_slice(&initrd.data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpio::{Archive, FileSpec};
+
+    /// Builds a newc cpio archive holding the given regular files
+    fn newc(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let trailer: (&str, &[u8]) = (CPIO_TRAILER, b"");
+        for (i, (name, contents)) in files.iter().chain([&trailer]).enumerate() {
+            let fields = [i + 1, 0o100644, 0, 0, 1, 0, contents.len(), 0, 0, 0, 0, name.len() + 1, 0];
+            data.extend_from_slice(b"070701");
+            for field in fields {
+                data.extend_from_slice(format!("{:08X}", field).as_bytes());
+            }
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+            data.resize(data.len().next_multiple_of(4), 0);
+            data.extend_from_slice(contents);
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+        data
+    }
+
+    fn load(directory: &Path, name: &str, data: &[u8]) -> Initrd {
+        let path = directory.join(name);
+        fs::write(&path, data).unwrap();
+        Initrd::load(&path).unwrap()
+    }
+
+    #[test]
+    fn walk_cpio_names() {
+        let data = newc(&[("./etc/crypttab", b"root /dev/sda2 none\n"), ("etc/key", b"secret")]);
+        let mut names = Vec::new();
+        assert_eq!(walk_cpio(&data, &mut names).unwrap(), data.len());
+        assert_eq!(names, ["etc/crypttab", "etc/key"]);
+
+        let mut padded = data.clone();
+        padded.extend_from_slice(&[0; 100]);
+        assert_eq!(walk_cpio(&padded, &mut Vec::new()).unwrap(), data.len());
+    }
+
+    #[test]
+    fn reject_truncated_cpio() {
+        let data = newc(&[("etc/crypttab", b"root /dev/sda2 none\n")]);
+        assert!(walk_cpio(&data[..data.len() - 12], &mut Vec::new()).is_err());
+        assert!(walk_cpio(&data[..50], &mut Vec::new()).is_err());
+        assert!(walk_cpio(b"070701 not a cpio header", &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn validate_concatenated_archives() {
+        let mut data = newc(&[("a", b"a")]);
+        data.resize(ALIGNMENT, 0);
+        data.extend_from_slice(&newc(&[("b", b"b")]));
+        data.resize(2 * ALIGNMENT, 0);
+        data.extend_from_slice(b"\x28\xb5\x2f\xfd compressed");
+
+        let (formats, names) = Initrd::validate(&data).unwrap();
+        assert_eq!(formats, ["cpio", "cpio", "zstd"]);
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[test]
+    fn reject_unknown_format() {
+        assert!(Initrd::validate(b"").is_err());
+        assert!(Initrd::validate(b"hello").is_err());
+
+        let mut data = newc(&[("a", b"a")]);
+        data.extend_from_slice(b"hello");
+        assert!(Initrd::validate(&data).is_err());
+    }
+
+    #[test]
+    fn detect_microcode() {
+        let dir = tempfile::tempdir().unwrap();
+        let microcode = newc(&[("kernel/x86/microcode/GenuineIntel.bin", b"ucode")]);
+
+        let initrd = load(dir.path(), "ucode.img", &microcode);
+        assert!(initrd.microcode);
+        assert!(!initrd.compressed);
+
+        // A compressed archive following the microcode, padded to a page like in a merged image
+        let mut merged = microcode.clone();
+        merged.resize(ALIGNMENT, 0);
+        merged.extend_from_slice(b"\x1f\x8b\x08\x00");
+        let initrd = load(dir.path(), "merged.img", &merged);
+        assert!(initrd.microcode);
+        assert!(initrd.compressed);
+
+        let initrd = load(dir.path(), "main.img", b"\x1f\x8b\x08\x00");
+        assert!(!initrd.microcode);
+        assert!(initrd.compressed);
+
+        let initrd = load(dir.path(), "other.img", &newc(&[("kernel/x86/microcode/README", b"")]));
+        assert!(!initrd.microcode);
+    }
+
+    #[test]
+    fn move_microcode_to_front() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = load(dir.path(), "main.img", b"\x1f\x8b\x08\x00");
+        let microcode = newc(&[("kernel/x86/microcode/AuthenticAMD.bin", b"ucode")]);
+        let microcode = load(dir.path(), "ucode.img", &microcode);
+
+        let mut initrds = [main, microcode];
+        assert_eq!(move_microcode_first(&mut initrds).len(), 1);
+        assert_eq!(initrds[0].path, dir.path().join("ucode.img"));
+        assert!(move_microcode_first(&mut initrds).is_empty());
+    }
+
+    #[test]
+    fn append_page_aligned() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut merged = Vec::new();
+        append(&mut merged, &load(dir.path(), "first.img", b"\x1f\x8b first"));
+        append(&mut merged, &load(dir.path(), "second.img", b"\x1f\x8b second"));
+        assert_eq!(merged.len(), ALIGNMENT + 9);
+        assert_eq!(&merged[..8], b"\x1f\x8b first");
+        assert_eq!(&merged[ALIGNMENT..], b"\x1f\x8b second");
+    }
+
+    #[test]
+    fn walk_generated_cpio() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("key");
+        fs::write(&source, "secret").unwrap();
+
+        let mut archive = Archive::new();
+        for destination in ["etc/crypttab", "etc/keys/root.key"] {
+            let spec: FileSpec = format!("{}:{}", source.display(), destination).parse().unwrap();
+            archive.add_file(&spec).unwrap();
+        }
+        let data = archive.finish().unwrap();
+
+        let mut names = Vec::new();
+        assert_eq!(walk_cpio(&data, &mut names).unwrap(), data.len());
+        assert_eq!(names, ["etc", "etc/crypttab", "etc/keys", "etc/keys/root.key"]);
+        assert!(!Initrd::parse(Path::new("generated"), data).unwrap().compressed);
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use structopt::StructOpt;
+
+use crate::authenticode::{self, hex};
+use crate::kernel;
+use crate::pe::PeImage;
+
+/// Lists the sections, embedded metadata and signatures of an EFI executable
+#[derive(Clone, StructOpt)]
+pub struct InspectArgs {
+    /// Path to the EFI executable to inspect
+    image: PathBuf,
+    /// Print the report as JSON
+    #[structopt(long)]
+    pub json: bool,
+}
+
+#[derive(Serialize)]
+struct SectionReport {
+    name: String,
+    virtual_address: u32,
+    virtual_size: u32,
+    file_offset: u32,
+    raw_size: u32,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum SignatureReport {
+    Decoded {
+        signer: String,
+        issuer: String,
+        serial: String,
+        digest_algorithm: String,
+        digest: String,
+        /// Absent if the image digest could not be computed
+        digest_matches: Option<bool>,
+    },
+    Undecodable {
+        error: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Report {
+    sections: Vec<SectionReport>,
+    cmdline: Option<String>,
+    os_release: Option<String>,
+    kernel_version: Option<String>,
+    kernel_architecture: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authenticode_digest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authenticode_digest_error: Option<String>,
+    signatures: Vec<SignatureReport>,
+}
+
+fn text_section(image: &PeImage, name: &str) -> Option<String> {
+    image
+        .find_section(name)
+        .map(|data| String::from_utf8_lossy(data).trim_end_matches(['\0', '\n']).to_owned())
+}
+
+fn report(image: &PeImage) -> Report {
+    let sections = image
+        .sections()
+        .iter()
+        .map(|section| SectionReport {
+            name: section.name.clone(),
+            virtual_address: section.virtual_address,
+            virtual_size: section.virtual_size,
+            file_offset: section.raw_offset,
+            raw_size: section.raw_size,
+            sha256: hex(&Sha256::digest(image.section_data(section))),
+        })
+        .collect();
+
+    // Keep going on errors, so that the rest of a broken image can still be inspected
+    let image_digest = image.authenticode_digest();
+    let signatures = match image.signatures() {
+        Ok(signatures) => signatures
+            .into_iter()
+            .map(|signature| match authenticode::describe(signature) {
+                Ok(info) => SignatureReport::Decoded {
+                    signer: info.signer,
+                    issuer: info.issuer,
+                    serial: info.serial,
+                    digest_algorithm: info.digest_algorithm,
+                    digest_matches: image_digest.as_ref().ok().map(|digest| *digest == info.digest),
+                    digest: hex(&info.digest),
+                },
+                Err(e) => SignatureReport::Undecodable { error: e.to_string() },
+            })
+            .collect(),
+        Err(e) => vec![SignatureReport::Undecodable { error: e.to_string() }],
+    };
+
+    Report {
+        sections,
+        cmdline: text_section(image, ".cmdline"),
+        os_release: text_section(image, ".osrel"),
+        kernel_version: image.find_section(".linux").and_then(kernel::version),
+        kernel_architecture: image.find_section(".linux").and_then(kernel::architecture),
+        authenticode_digest: image_digest.as_ref().ok().map(|digest| hex(digest)),
+        authenticode_digest_error: image_digest.err().map(|e| e.to_string()),
+        signatures,
+    }
+}
+
+fn print_text(name: &str, text: &Option<String>) {
+    match text {
+        Some(text) => {
+            println!("{}:", name);
+            for line in text.lines() {
+                println!("    {}", line);
+            }
+        }
+        None => println!("{}: none", name),
+    }
+}
+
+fn print_report(report: &Report) {
+    println!("Sections:");
+    println!(
+        "    {:<8}  {:>10}  {:>10}  {:>10}  SHA-256",
+        "Name", "VMA", "Size", "Offset"
+    );
+    for section in &report.sections {
+        println!(
+            "    {:<8}  {:#010x}  {:#010x}  {:#010x}  {}",
+            section.name, section.virtual_address, section.virtual_size, section.file_offset, section.sha256
+        );
+    }
+
+    print_text("Command line", &report.cmdline);
+    print_text("OS release", &report.os_release);
+    println!(
+        "Kernel version: {}",
+        report.kernel_version.as_deref().unwrap_or("unknown")
+    );
+    println!(
+        "Kernel architecture: {}",
+        report.kernel_architecture.unwrap_or("unknown")
+    );
+    match (&report.authenticode_digest, &report.authenticode_digest_error) {
+        (Some(digest), _) => println!("Authenticode digest: {}", digest),
+        (None, error) => println!("Authenticode digest: error: {}", error.as_deref().unwrap_or("unknown")),
+    }
+
+    if report.signatures.is_empty() {
+        println!("Signatures: none");
+    } else {
+        println!("Signatures:");
+    }
+    for signature in &report.signatures {
+        match signature {
+            SignatureReport::Decoded {
+                signer,
+                issuer,
+                serial,
+                digest_algorithm,
+                digest,
+                digest_matches,
+            } => {
+                println!("    Signer: {}", signer);
+                println!("    Issuer: {}", issuer);
+                println!("    Serial: {}", serial);
+                println!(
+                    "    Digest: {} {} ({})",
+                    digest_algorithm,
+                    digest,
+                    match digest_matches {
+                        Some(true) => "matches image",
+                        Some(false) => "DOES NOT match image",
+                        None => "image digest unknown",
+                    }
+                );
+            }
+            SignatureReport::Undecodable { error } => println!("    Undecodable signature: {}", error),
+        }
+    }
+}
+
+pub fn inspect(args: InspectArgs) -> io::Result<()> {
+    let image = PeImage::parse(fs::read(&args.image)?)?;
+    let report = report(&image);
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&report).map_err(io::Error::other)?;
+        println!("{}", json);
+    } else {
+        print_report(&report);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_keeps_going_with_certificates_past_end_of_file() {
+        let mut image = PeImage::parse(crate::pe::tests::test_image()).unwrap();
+        image.add_section(".cmdline", b"quiet\0").unwrap();
+        let mut data = image.into_bytes();
+
+        // Point the security directory entry at a certificate table that runs past the end of the file
+        let security_entry = 0x58 + 112 + 4 * 8;
+        let table_offset = data.len() as u32 - 8;
+        data[security_entry..security_entry + 4].copy_from_slice(&table_offset.to_le_bytes());
+        data[security_entry + 4..security_entry + 8].copy_from_slice(&0x100u32.to_le_bytes());
+
+        let report = report(&PeImage::parse(data).unwrap());
+        assert_eq!(report.sections.len(), 2);
+        assert_eq!(report.cmdline.as_deref(), Some("quiet"));
+        assert!(report.authenticode_digest.is_none());
+        assert!(report.authenticode_digest_error.is_some());
+        assert!(matches!(report.signatures[..], [SignatureReport::Undecodable { .. }]));
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::pe;
+
+const LINUX_BANNER: &[u8] = b"Linux version ";
+
+/// Offset of the magic number in the arm64 and RISC-V `Image` headers
+const IMAGE_MAGIC_OFFSET: usize = 0x38;
+/// Offset of the magic number in the 32-bit ARM `zImage` header
+const ZIMAGE_MAGIC_OFFSET: usize = 0x24;
+
+/// Set in the x86 boot protocol's `xloadflags` if the kernel has a 64-bit entry point
+const XLF_KERNEL_64: u16 = 1 << 0;
+
+fn is_x86_boot_image(image: &[u8]) -> bool {
+    image.get(0x202..0x206) == Some(b"HdrS")
+}
+
+/// EFI zboot images wrap a compressed kernel, which the stub decompresses at boot
+fn is_zboot_image(image: &[u8]) -> bool {
+    image.starts_with(b"MZ") && image.get(4..8) == Some(b"zimg")
+}
+
+/// Returns the NUL-terminated string at `offset`, up to the first whitespace
+fn release_at(image: &[u8], offset: usize) -> Option<String> {
+    let bytes = image.get(offset..)?;
+    let end = bytes
+        .iter()
+        .position(|&b| b == 0 || b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let release = std::str::from_utf8(&bytes[..end]).ok()?;
+
+    if release.is_empty() {
+        None
+    } else {
+        Some(release.to_owned())
+    }
+}
+
+/// Reads the kernel release from the x86 boot protocol header, falling back to the
+/// "Linux version" banner for uncompressed images
+pub fn version(image: &[u8]) -> Option<String> {
+    // The release is only stored in the compressed payload
+    if is_zboot_image(image) {
+        return None;
+    }
+
+    if let (true, Some(&[low, high])) = (is_x86_boot_image(image), image.get(0x20e..0x210)) {
+        let offset = u16::from_le_bytes([low, high]) as usize;
+        if offset != 0 {
+            return release_at(image, 0x200 + offset);
+        }
+    }
+
+    image
+        .windows(LINUX_BANNER.len())
+        .position(|window| window == LINUX_BANNER)
+        .and_then(|offset| release_at(image, offset + LINUX_BANNER.len()))
+}
+
+/// Works out the architecture the kernel is built for, from its PE header if it has an EFI stub,
+/// or else from its boot header
+pub fn architecture(image: &[u8]) -> Option<&'static str> {
+    if let Some(machine) = pe::machine(image) {
+        return pe::architecture_name(machine);
+    }
+
+    if is_x86_boot_image(image) {
+        let xloadflags = u16::from_le_bytes(image.get(0x236..0x238)?.try_into().ok()?);
+        return Some(if xloadflags & XLF_KERNEL_64 != 0 { "x86_64" } else { "x86" });
+    }
+
+    match image.get(IMAGE_MAGIC_OFFSET..IMAGE_MAGIC_OFFSET + 4)? {
+        b"ARM\x64" => return Some("aarch64"),
+        b"RSC\x05" => return Some("riscv64"),
+        _ => {}
+    }
+
+    if image.get(ZIMAGE_MAGIC_OFFSET..ZIMAGE_MAGIC_OFFSET + 4)? == 0x016f_2818u32.to_le_bytes() {
+        return Some("arm");
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the start of an x86 bzImage, with its release string at `0x200 + 0x100`
+    fn x86_image(xloadflags: u16) -> Vec<u8> {
+        let mut image = vec![0u8; 0x400];
+        image[0x202..0x206].copy_from_slice(b"HdrS");
+        image[0x20e..0x210].copy_from_slice(&0x100u16.to_le_bytes());
+        image[0x236..0x238].copy_from_slice(&xloadflags.to_le_bytes());
+        image[0x300..0x31e].copy_from_slice(b"6.1.0-13-amd64 (debian-kernel@");
+        image
+    }
+
+    fn image_with_magic(offset: usize, magic: &[u8]) -> Vec<u8> {
+        let mut image = vec![0u8; 0x200];
+        image[offset..offset + magic.len()].copy_from_slice(magic);
+        image
+    }
+
+    #[test]
+    fn x86_version_and_architecture() {
+        assert_eq!(version(&x86_image(XLF_KERNEL_64)).as_deref(), Some("6.1.0-13-amd64"));
+        assert_eq!(architecture(&x86_image(XLF_KERNEL_64)), Some("x86_64"));
+        assert_eq!(architecture(&x86_image(0)), Some("x86"));
+    }
+
+    #[test]
+    fn version_from_banner() {
+        let mut image = image_with_magic(IMAGE_MAGIC_OFFSET, b"ARM\x64");
+        image.extend_from_slice(b"Linux version 6.6.0-arm64 (builder@host) #1 SMP\0");
+        assert_eq!(version(&image).as_deref(), Some("6.6.0-arm64"));
+        assert_eq!(version(&image_with_magic(IMAGE_MAGIC_OFFSET, b"ARM\x64")), None);
+    }
+
+    #[test]
+    fn boot_header_architectures() {
+        assert_eq!(architecture(&image_with_magic(IMAGE_MAGIC_OFFSET, b"ARM\x64")), Some("aarch64"));
+        assert_eq!(architecture(&image_with_magic(IMAGE_MAGIC_OFFSET, b"RSC\x05")), Some("riscv64"));
+        assert_eq!(
+            architecture(&image_with_magic(ZIMAGE_MAGIC_OFFSET, &0x016f_2818u32.to_le_bytes())),
+            Some("arm")
+        );
+        assert_eq!(architecture(&[0u8; 0x200]), None);
+        assert_eq!(architecture(b"short"), None);
+    }
+
+    #[test]
+    fn pe_architecture() {
+        assert_eq!(architecture(&pe::tests::test_image()), Some("x86_64"));
+    }
+
+    #[test]
+    fn zboot_has_no_version() {
+        let mut image = pe::tests::test_image();
+        image[4..8].copy_from_slice(b"zimg");
+        image.extend_from_slice(b"Linux version 6.6.0 (builder@host)\0");
+        assert_eq!(version(&image), None);
+    }
+}
@@ -13,89 +13,380 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::ffi::OsString;
-use std::fs::{self, File};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
 use std::io::{self, IsTerminal, Write};
-use std::iter::Iterator;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use structopt::clap;
 use structopt::StructOpt;
-use tempfile::NamedTempFile;
 
-macro_rules! os {
-    ($s:tt) => {
-        std::ffi::OsStr::new($s)
-    };
-}
+use crate::authenticode::Signer;
+use crate::cmdline::Cmdline;
+use crate::config::{Config, Profile};
+use crate::cpio::{Archive, FileSpec};
+use crate::extract::ExtractArgs;
+use crate::initrd::Initrd;
+use crate::inspect::InspectArgs;
+use crate::osrel::OsRelease;
+use crate::pe::PeImage;
+use crate::rollback::RollbackArgs;
+use crate::verify::VerifyArgs;
+
+mod atomic;
+mod authenticode;
+mod backup;
+mod cmdline;
+mod config;
+mod cpio;
+mod discover;
+mod disk;
+mod extract;
+mod initrd;
+mod inspect;
+mod kernel;
+mod osrel;
+mod pe;
+mod rollback;
+mod sbat;
+mod splash;
+mod verify;
 
 /// Creates standalone EFI executables from Linux kernel images
 ///
 /// WARNING: This software is deprecated. Consider using ukify, dracut or mkinitcpio instead.
-#[derive(StructOpt)]
+#[derive(Clone, StructOpt)]
 #[structopt(author)]
 struct Args {
     /// Path to the kernel image
     #[structopt(short, long)]
-    kernel: PathBuf,
+    kernel: Option<PathBuf>,
     /// Path to file containing the default command line arguments
     #[structopt(short, long)]
-    cmdline: PathBuf,
+    cmdline: Option<PathBuf>,
+    /// Default command line arguments, given inline instead of in a file
+    #[structopt(long, conflicts_with = "cmdline")]
+    cmdline_string: Option<String>,
+    /// Use the running system's command line (from /proc/cmdline) as the default arguments
+    #[structopt(long, conflicts_with_all = &["cmdline", "cmdline-string"])]
+    cmdline_from_proc: bool,
+    /// Set root= to the current root filesystem, identified by its UUID or PARTUUID
+    #[structopt(long, possible_values = &["uuid", "partuuid"])]
+    root_from_mount: Option<String>,
+    /// Append arguments to the command line
+    #[structopt(long, number_of_values = 1)]
+    cmdline_append: Vec<String>,
+    /// Remove an argument from the command line, either by name or as an exact name=value
+    #[structopt(long, number_of_values = 1)]
+    cmdline_remove: Vec<String>,
     /// Path to the output file
     #[structopt(short, long)]
-    output: PathBuf,
+    output: Option<PathBuf>,
     /// Path to the initramfs file(s) to include
     #[structopt(short, long)]
     initrd: Vec<PathBuf>,
-    /// Path to the systemd-boot stub file
-    #[cfg(target_arch = "aarch64")]
-    #[structopt(short = "S", long, default_value = "/usr/lib/systemd/boot/efi/linuxaa64.efi.stub")]
-    stub: PathBuf,
-    /// Path to the systemd-boot stub file
-    #[cfg(target_arch = "arm")]
-    #[structopt(short = "S", long, default_value = "/usr/lib/systemd/boot/efi/linuxarm.efi.stub")]
-    stub: PathBuf,
-    /// Path to the systemd-boot stub file
-    #[cfg(target_arch = "x86")]
-    #[structopt(short = "S", long, default_value = "/usr/lib/systemd/boot/efi/linuxia32.efi.stub")]
-    stub: PathBuf,
-    /// Path to the systemd-boot stub file
-    #[cfg(target_arch = "x86_64")]
-    #[structopt(short = "S", long, default_value = "/usr/lib/systemd/boot/efi/linuxx64.efi.stub")]
-    stub: PathBuf,
-    /// Path to the systemd-boot stub file
-    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64", target_arch = "x86", target_arch = "x86_64")))]
+    /// Add the contents of a directory to the initramfs
+    #[structopt(long, number_of_values = 1)]
+    initrd_dir: Vec<PathBuf>,
+    /// Add a file to the initramfs, given as <src>:<dest>[:mode], e.g. /etc/keyfile:/etc/keyfile:0400
+    #[structopt(long, number_of_values = 1)]
+    initrd_file: Vec<FileSpec>,
+    /// Add the distribution's microcode image for the running CPU (/boot/amd-ucode.img or /boot/intel-ucode.img)
+    #[structopt(long)]
+    auto_ucode: bool,
+    /// Path to the systemd-boot stub file [default: /usr/lib/systemd/boot/efi/linux<arch>.efi.stub]
     #[structopt(short = "S", long)]
-    stub: PathBuf,
+    stub: Option<PathBuf>,
+    /// Path to the os-release file to embed [default: /etc/os-release]
+    #[structopt(long)]
+    os_release: Option<PathBuf>,
+    /// Override an os-release field, e.g. PRETTY_NAME="Arch (hardened)"
+    #[structopt(long, number_of_values = 1)]
+    os_release_set: Vec<String>,
+    /// Kernel release to embed as .uname [default: read from the kernel image]
+    #[structopt(long)]
+    uname: Option<String>,
+    /// Path to SBAT data (CSV) to embed, merged with the stub's own
+    #[structopt(long)]
+    sbat: Option<PathBuf>,
+    /// Path to a BMP image to show while booting
+    #[structopt(long)]
+    splash: Option<PathBuf>,
     /// Make a backup of the previous output if it exists
     #[structopt(short, long)]
     backup: Option<PathBuf>,
+    /// Keep backups of the previous output in this directory, named after the kernel they contain
+    #[structopt(long, conflicts_with = "backup")]
+    backup_dir: Option<PathBuf>,
+    /// Number of backups to keep in --backup-dir [default: 3]
+    #[structopt(long)]
+    keep: Option<usize>,
     /// Path to the .key and .crt files (in this order) to sign the executable with
     #[structopt(short, long, number_of_values = 2)]
     sign: Option<Vec<PathBuf>>,
     /// Overwrite output file if it already exists
     #[structopt(short = "f", long = "force")]
     overwrite: bool,
+    /// Path to the configuration file
+    #[structopt(long, default_value = config::DEFAULT_PATH)]
+    config: PathBuf,
+    /// Build the named profile from the configuration file, with the other options taking precedence
+    #[structopt(short, long)]
+    profile: Option<String>,
+    /// Build every profile from the configuration file, with the other options taking precedence
+    #[structopt(long, conflicts_with_all = &["profile", "kernel", "output", "backup"])]
+    all: bool,
+    /// Build every kernel in /boot and /usr/lib/modules, naming the outputs after this template, e.g.
+    /// /boot/efi/EFI/Linux/{id}-{kver}.efi (variables: {kver}, {name}, {id}, {version_id}, {pretty_name})
+    #[structopt(long, conflicts_with_all = &["all", "profile", "kernel", "output", "backup"])]
+    output_template: Option<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+/// Stub used when neither the command line nor the profile names one
+#[cfg(target_arch = "aarch64")]
+const DEFAULT_STUB: Option<&str> = Some("/usr/lib/systemd/boot/efi/linuxaa64.efi.stub");
+#[cfg(target_arch = "arm")]
+const DEFAULT_STUB: Option<&str> = Some("/usr/lib/systemd/boot/efi/linuxarm.efi.stub");
+#[cfg(target_arch = "x86")]
+const DEFAULT_STUB: Option<&str> = Some("/usr/lib/systemd/boot/efi/linuxia32.efi.stub");
+#[cfg(target_arch = "x86_64")]
+const DEFAULT_STUB: Option<&str> = Some("/usr/lib/systemd/boot/efi/linuxx64.efi.stub");
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64", target_arch = "x86", target_arch = "x86_64")))]
+const DEFAULT_STUB: Option<&str> = None;
+
+#[derive(Clone, StructOpt)]
+enum Command {
+    Extract(ExtractArgs),
+    Inspect(InspectArgs),
+    Rollback(RollbackArgs),
+    Verify(VerifyArgs),
 }
 
 #[paw::main]
-fn main(args: Args) -> io::Result<()> {
-    println!("sigen {}", option_env!("CARGO_PKG_VERSION").unwrap_or(""));
-    if io::stdout().is_terminal() {
-        print!("\x1b[31;1mWARNING: \x1b[0m");
-    } else {
-        print!("WARNING: ");
+fn main(mut args: Args) -> io::Result<()> {
+    // Keep machine-readable output parseable
+    if !matches!(args.command, Some(Command::Inspect(InspectArgs { json: true, .. }))) {
+        println!("sigen {}", option_env!("CARGO_PKG_VERSION").unwrap_or(""));
+        if io::stdout().is_terminal() {
+            print!("\x1b[31;1mWARNING: \x1b[0m");
+        } else {
+            print!("WARNING: ");
+        }
+        println!("This software is deprecated. Consider using ukify, dracut or mkinitcpio instead.");
+    }
+
+    // Only builds and rollbacks replace an output, the other commands leave nothing behind
+    if matches!(args.command, None | Some(Command::Rollback(_))) {
+        atomic::remove_on_signal()?;
+    }
+
+    match args.command {
+        Some(Command::Extract(extract_args)) => extract::extract(extract_args),
+        Some(Command::Inspect(inspect_args)) => inspect::inspect(inspect_args),
+        Some(Command::Rollback(rollback_args)) => rollback::rollback(rollback_args),
+        Some(Command::Verify(verify_args)) => verify::verify(verify_args),
+        None if args.all => build_all(args),
+        None => match args.output_template.take() {
+            Some(template) => build_discovered(args, &template),
+            None => {
+                if let Some(ref name) = args.profile {
+                    let profile = Config::load(&args.config)?.take_profile(name)?;
+                    apply_profile(&mut args, profile);
+                }
+                build(args)
+            }
+        },
+    }
+}
+
+/// Runs several builds, carrying on after failures, and prints a summary of their results
+fn build_each(what: &str, builds: Vec<(String, Args)>) -> io::Result<()> {
+    let mut results = Vec::new();
+    for (name, args) in builds {
+        println!("\n==> Building {} {}", what, name);
+
+        let result = build(args);
+        if let Err(ref err) = result {
+            eprintln!("Failed to build {} {}: {}", what, name, err);
+        }
+        results.push((name, result));
+    }
+
+    let header = format!("{}{}", what[..1].to_uppercase(), &what[1..]);
+    let width = results.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max(header.len());
+    println!("\n{:width$}  Result", header, width = width);
+    for (name, result) in &results {
+        match result {
+            Ok(()) => println!("{:width$}  ok", name, width = width),
+            Err(err) => println!("{:width$}  failed: {}", name, err, width = width),
+        }
+    }
+
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        return Err(io::Error::other(format!("{} of {} {}s failed to build", failed, results.len(), what)));
+    }
+    Ok(())
+}
+
+/// Builds every profile in the configuration file
+fn build_all(args: Args) -> io::Result<()> {
+    let config = Config::load(&args.config)?;
+    if config.profiles.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No profiles in configuration file {}", args.config.display()),
+        ));
+    }
+
+    let builds = config
+        .profiles
+        .into_iter()
+        .map(|(name, profile)| {
+            let mut profile_args = args.clone();
+            profile_args.profile = Some(name.clone());
+            apply_profile(&mut profile_args, profile);
+            (name, profile_args)
+        })
+        .collect();
+    build_each("profile", builds)
+}
+
+/// Builds every installed kernel, naming the outputs after the template
+fn build_discovered(args: Args, template: &str) -> io::Result<()> {
+    let os_release = load_os_release(&args)?;
+    let kernels = discover::find_kernels()?;
+    if kernels.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No kernels found in /boot or /usr/lib/modules",
+        ));
+    }
+
+    let mut builds = Vec::new();
+    let mut outputs: HashMap<String, PathBuf> = HashMap::new();
+    for kernel in kernels {
+        let variables = [
+            ("kver", Some(kernel.version.clone())),
+            ("name", Some(kernel.name.clone())),
+            ("id", os_release.get("ID")),
+            ("version_id", os_release.get("VERSION_ID")),
+            ("pretty_name", os_release.get("PRETTY_NAME")),
+        ];
+        let output = discover::expand(template, &variables)
+            .map_err(|reason| io::Error::new(io::ErrorKind::InvalidInput, reason))?;
+
+        println!("Found kernel {} ({}) -> {}", kernel.path.display(), kernel.version, output);
+        // Checked before anything is built, as later kernels would silently replace earlier ones
+        if let Some(other) = outputs.insert(output.clone(), kernel.path.clone()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Output template \"{}\" expands to {} for both {} and {}, include {{kver}} or {{name}} in it",
+                    template,
+                    output,
+                    other.display(),
+                    kernel.path.display()
+                ),
+            ));
+        }
+        let mut kernel_args = args.clone();
+        match kernel.initrd {
+            Some(initrd) => kernel_args.initrd.push(initrd),
+            None => eprintln!("Warning: No initramfs found for {}", kernel.path.display()),
+        }
+        kernel_args.kernel = Some(kernel.path);
+        kernel_args.output = Some(PathBuf::from(output));
+        builds.push((kernel.name, kernel_args));
     }
-    println!("This software is deprecated. Consider using ukify, dracut or mkinitcpio instead.");
 
-    if !args.stub.is_file() {
+    build_each("kernel", builds)
+}
+
+/// Fills in the options that were not given on the command line from a profile
+fn apply_profile(args: &mut Args, profile: Profile) {
+    args.kernel = args.kernel.take().or(profile.kernel);
+    if args.initrd.is_empty() {
+        args.initrd = profile.initrd;
+    }
+    if args.cmdline.is_none() && args.cmdline_string.is_none() && !args.cmdline_from_proc {
+        args.cmdline = profile.cmdline;
+        args.cmdline_string = profile.cmdline_string;
+        args.cmdline_from_proc = profile.cmdline_from_proc;
+    }
+    args.stub = args.stub.take().or(profile.stub);
+    args.output = args.output.take().or(profile.output);
+    if args.backup.is_none() && args.backup_dir.is_none() {
+        args.backup = profile.backup;
+        args.backup_dir = profile.backup_dir;
+    }
+    args.keep = args.keep.or(profile.keep);
+    args.sign = args.sign.take().or(profile.sign.map(Vec::from));
+    args.overwrite |= profile.overwrite;
+}
+
+/// Exits with a usage error about an argument that is only optional when a subcommand is given
+fn missing_argument(name: &str) -> ! {
+    clap::Error::with_description(
+        &format!("The following required argument was not provided: {}", name),
+        clap::ErrorKind::MissingRequiredArgument,
+    )
+    .exit()
+}
+
+/// Reports an argument that neither the command line nor the profile being built provides
+fn missing(profile: Option<&str>, name: &str) -> io::Error {
+    match profile {
+        Some(profile) => io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Profile {} does not set {}", profile, name),
+        ),
+        None => missing_argument(name),
+    }
+}
+
+fn required<'a>(value: &'a Option<PathBuf>, name: &str, profile: Option<&str>) -> io::Result<&'a Path> {
+    value.as_deref().ok_or_else(|| missing(profile, &format!("--{}", name)))
+}
+
+/// Reads the os-release file to embed, with the overrides applied
+fn load_os_release(args: &Args) -> io::Result<OsRelease> {
+    let mut os_release = OsRelease::load(args.os_release.as_deref())?;
+    for assignment in &args.os_release_set {
+        os_release.set(assignment)?;
+    }
+    Ok(os_release)
+}
+
+fn build(args: Args) -> io::Result<()> {
+    let profile = args.profile.as_deref();
+    let kernel = required(&args.kernel, "kernel", profile)?;
+    let keep = args.keep.unwrap_or(3);
+    if keep == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--keep must be at least 1, so that a known-good image is always kept",
+        ));
+    }
+    let output = required(&args.output, "output", profile)?;
+    let stub = match (&args.stub, DEFAULT_STUB) {
+        (Some(path), _) => path.as_path(),
+        (None, Some(path)) => Path::new(path),
+        (None, None) => return Err(missing(profile, "--stub")),
+    };
+
+    if !stub.is_file() {
         return Err(io::Error::new(
             io::ErrorKind::NotFound,
-            format!("Failed to find stub {}", args.stub.display()),
+            format!("Failed to find stub {}", stub.display()),
         ));
     }
 
-    if let Some(ref v) = args.sign {
+    let signer = if let Some(ref v) = args.sign {
         let key = &v[0];
         let crt = &v[1];
 
@@ -113,28 +404,106 @@ fn main(args: Args) -> io::Result<()> {
             ));
         }
 
-        Command::new("sbsign").arg("-V").status()?;
+        Some(Signer::load(key, crt)?)
+    } else {
+        None
+    };
+
+    if !kernel.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Failed to find kernel image {}", kernel.display()),
+        ));
+    }
+
+    let mut image = PeImage::parse(fs::read(stub)?)?;
+    let kernel_image = fs::read(kernel)?;
+    let sbat = match args.sbat {
+        Some(ref path) => Some(sbat::merge(image.find_section(".sbat"), path)?),
+        None => None,
+    };
+
+    let uname = args.uname.clone().or_else(|| kernel::version(&kernel_image));
+    if uname.is_none() {
+        eprintln!("Warning: Failed to read the kernel release, pass --uname to embed a .uname section");
+    }
+
+    let kernel_arch = kernel::architecture(&kernel_image);
+    let stub_arch = pe::architecture_name(image.machine());
+    if let (Some(kernel_arch), Some(stub_arch)) = (kernel_arch, stub_arch) {
+        if kernel_arch == "x86_64" && stub_arch == "x86" {
+            // EFI mixed mode, i.e. a 64-bit kernel started from 32-bit firmware
+            eprintln!(
+                "Warning: Kernel image {} is built for x86_64, but stub {} is for x86, which only boots on \
+                 32-bit firmware using EFI mixed mode",
+                kernel.display(),
+                stub.display()
+            );
+        } else if kernel_arch != stub_arch {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Kernel image {} is built for {}, but stub {} is for {}",
+                    kernel.display(),
+                    kernel_arch,
+                    stub.display(),
+                    stub_arch
+                ),
+            ));
+        }
     }
 
-    Command::new("objcopy").arg("-V").status()?;
+    let mut cmdline = match (&args.cmdline, &args.cmdline_string) {
+        (Some(path), _) => Cmdline::parse(&fs::read_to_string(path)?),
+        (None, Some(text)) => Cmdline::parse(text),
+        (None, None) if args.cmdline_from_proc => Cmdline::from_proc()?,
+        (None, None) => return Err(missing(profile, "--cmdline, --cmdline-string or --cmdline-from-proc")),
+    };
+    if let Some(ref kind) = args.root_from_mount {
+        cmdline.set(&disk::root_parameter(kind)?);
+    }
+    for text in &args.cmdline_append {
+        cmdline.append(text);
+    }
+    for name in &args.cmdline_remove {
+        cmdline.remove(name);
+    }
 
-    if !args.kernel.is_file() {
+    let lints = cmdline.lint(signer.is_some());
+    for lint in &lints {
+        eprintln!("{}: {}", if lint.fatal { "Error" } else { "Warning" }, lint.message);
+    }
+    if lints.iter().any(|lint| lint.fatal) {
         return Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("Failed to find kernel image {}", args.kernel.display()),
+            io::ErrorKind::InvalidInput,
+            "Refusing to embed a command line that would not boot as intended",
         ));
     }
 
-    print!("\nCreating combined initramfs...");
-    io::stdout().flush()?;
+    let os_release = load_os_release(&args)?;
 
-    let mut merged_initrd = NamedTempFile::new()?;
+    let splash = match args.splash {
+        Some(ref path) => Some(splash::load(path)?),
+        None => None,
+    };
 
-    for path in &args.initrd {
-        match File::open(path) {
-            Ok(mut file) => {
-                io::copy(&mut file, &mut merged_initrd)?;
+    let mut initrd_paths = args.initrd.clone();
+    if args.auto_ucode {
+        match initrd::microcode_image()? {
+            Some(path) => {
+                if !initrd_paths.contains(&path) {
+                    initrd_paths.insert(0, path);
+                }
             }
+            None => eprintln!("Warning: No microcode image found for this CPU"),
+        }
+    }
+
+    let mut initrds = Vec::new();
+    for path in &initrd_paths {
+        match Initrd::load(path) {
+            Ok(initrd) => initrds.push(initrd),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => return Err(err),
             Err(err) => {
                 eprintln!("Failed to find initramfs image {}", path.display());
                 return Err(err);
@@ -142,154 +511,128 @@ fn main(args: Args) -> io::Result<()> {
         }
     }
 
-    println!(" done");
+    // Generated files go last, so that they take precedence over those in the given images
+    let mut archive = Archive::new();
+    for directory in &args.initrd_dir {
+        if !directory.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Failed to find initramfs directory {}", directory.display()),
+            ));
+        }
+        archive.add_directory_contents(directory)?;
+    }
+    for spec in &args.initrd_file {
+        archive.add_file(spec)?;
+    }
+    if !archive.is_empty() {
+        initrds.push(Initrd::parse(Path::new("generated archive"), archive.finish()?)?);
+    }
 
-    merged_initrd.as_file_mut().sync_all()?;
-    let merged_initrd = merged_initrd.into_temp_path();
-    let merged_initrd_path = merged_initrd.keep()?;
+    for warning in initrd::move_microcode_first(&mut initrds) {
+        eprintln!("Warning: {}", warning);
+    }
 
-    if args.output.is_file() {
-        match args.backup {
-            Some(path) => {
-                if path.is_file() && !args.overwrite {
-                    return Err(io::Error::new(
-                        io::ErrorKind::AlreadyExists,
-                        "Backup file already exists, pass -f to overwrite",
-                    ));
-                }
+    print!("\nCreating combined initramfs...");
+    io::stdout().flush()?;
 
-                fs::copy(&args.output, &path)?;
-                fs::remove_file(&args.output)?;
-            }
-            None => {
-                if args.overwrite {
-                    fs::remove_file(&args.output)?;
-                } else {
-                    return Err(io::Error::new(
-                        io::ErrorKind::AlreadyExists,
-                        "Output file already exists, pass -f to overwrite",
-                    ));
-                }
-            }
-        }
+    let mut merged_initrd = Vec::new();
+    for initrd in &initrds {
+        initrd::append(&mut merged_initrd, initrd);
     }
 
+    println!(" done");
+
     print!("Creating standalone executable...");
     io::stdout().flush()?;
 
-    let mut cmdline_arg = OsString::new();
-    cmdline_arg.push(".cmdline=");
-    cmdline_arg.push(args.cmdline);
-
-    let mut kernel_arg = OsString::new();
-    kernel_arg.push(".linux=");
-    kernel_arg.push(args.kernel);
-
-    let mut initrd_arg = OsString::new();
-    initrd_arg.push(".initrd=");
-    initrd_arg.push(&merged_initrd_path);
-
-    let mut command = Command::new("objcopy");
-    command.args(&[
-        os!("--add-section"),
-        os!(".osrel=/etc/os-release"),
-        os!("--change-section-vma"),
-        os!(".osrel=0x20000"),
-
-        os!("--add-section"),
-        &cmdline_arg,
-        os!("--change-section-vma"),
-        os!(".cmdline=0x30000"),
-
-        os!("--add-section"),
-        os!(".splash=/dev/null"),
-        os!("--change-section-vma"),
-        os!(".splash=0x40000"),
-
-        os!("--add-section"),
-        &kernel_arg,
-        os!("--change-section-vma"),
-        os!(".linux=0x2000000"),
-
-        os!("--add-section"),
-        &initrd_arg,
-        os!("--change-section-vma"),
-        os!(".initrd=0x3000000"),
-
-        args.stub.as_os_str(),
-        args.output.as_os_str(),
-    ]);
-
-    match command.status() {
-        Ok(status) => {
-            if !status.success() {
-                match status.code() {
-                    Some(code) => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("objcopy terminated with code {}", code),
-                        ))
-                    }
-                    None => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "objcopy terminated by signal",
-                        ))
-                    }
-                }
-            }
+    image.add_section(".osrel", &os_release.to_bytes())?;
+    image.add_section(".cmdline", &cmdline.to_bytes())?;
+    if let Some(ref uname) = uname {
+        image.add_section(".uname", uname.as_bytes())?;
+    }
+    if let Some(ref splash) = splash {
+        image.add_section(".splash", splash)?;
+    }
+    if let Some(ref sbat) = sbat {
+        if image.find_section(".sbat").is_some() {
+            image.replace_section(".sbat", sbat)?;
+        } else {
+            image.add_section(".sbat", sbat)?;
         }
-        Err(err) => return Err(err),
     }
+    image.add_section(".linux", &kernel_image)?;
+    image.add_section(".initrd", &merged_initrd)?;
+
+    // Record the build time, or SOURCE_DATE_EPOCH for reproducible builds
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+    image.set_timestamp(build_time.min(u32::MAX as u64) as u32);
 
     println!(" done");
-    fs::remove_file(merged_initrd_path)?;
 
-    if let Some(v) = args.sign {
+    if let Some(ref signer) = signer {
         print!("Signing executable...");
         io::stdout().flush()?;
 
-        let key = &v[0];
-        let crt = &v[1];
+        let signature = signer.sign(&image.authenticode_digest()?)?;
+        image.append_certificate(&signature)?;
 
-        let mut sign_command = Command::new("sbsign");
-        sign_command.args(&[
-            os!("--key"),
-            key.as_os_str(),
-
-            os!("--cert"),
-            crt.as_os_str(),
-
-            os!("--output"),
-            args.output.as_os_str(),
-
-            args.output.as_os_str(),
-        ]);
-
-        match sign_command.status() {
-            Ok(status) => {
-                if !status.success() {
-                    match status.code() {
-                        Some(code) => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                format!("sbsign terminated with code {}", code),
-                            ))
-                        }
-                        None => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                "sbsign terminated by signal",
-                            ))
-                        }
-                    }
-                }
+        println!(" done");
+    }
+
+    let image = image.into_bytes();
+
+    // Backups in --backup-dir get unique names, and make it safe to replace the output
+    if output.is_file() && args.backup_dir.is_none() {
+        match args.backup {
+            Some(ref path) if path.is_file() && !args.overwrite => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "Backup file already exists, pass -f to overwrite",
+                ));
+            }
+            None if !args.overwrite => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "Output file already exists, pass -f to overwrite",
+                ));
             }
-            Err(err) => return Err(err),
+            _ => {}
         }
+    }
 
-        println!(" done");
+    // Make sure everything fits before writing anything, as the ESP is usually small
+    let mut writes = vec![(output.to_owned(), image.len() as u64)];
+    let backup_path = match args.backup_dir {
+        Some(ref directory) if output.is_file() => Some(backup::path(output, directory)?),
+        _ => None,
+    };
+    if output.is_file() {
+        let size = fs::metadata(output)?.len();
+        if let Some(ref path) = args.backup {
+            writes.push((path.clone(), size));
+        }
+        if let Some(ref path) = backup_path {
+            writes.push((path.clone(), size));
+        }
+    }
+    disk::check_free_space(&writes)?;
+
+    if output.is_file() {
+        if let Some(ref path) = args.backup {
+            atomic::copy(output, path)?;
+        }
+        if let Some(ref path) = backup_path {
+            let trust = signer.as_ref().map(Signer::trust_store);
+            backup::store(output, path, keep, trust.as_ref())?;
+        }
     }
 
+    // The previous image stays in place until the new one is complete
+    atomic::write(output, &image)?;
+
     Ok(())
 }
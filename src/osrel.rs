@@ -0,0 +1,251 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_PATHS: &[&str] = &["/etc/os-release", "/usr/lib/os-release"];
+
+/// An os-release file, kept line by line so that comments and formatting survive
+pub struct OsRelease {
+    lines: Vec<String>,
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses a shell-style os-release value, returning its unquoted form
+fn parse_value(raw: &str) -> Result<String, &'static str> {
+    let mut value = String::new();
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some(c) => value.push(c),
+                    None => return Err("unterminated single quote"),
+                }
+            },
+            '"' => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c @ ('"' | '\\' | '$' | '`')) => value.push(c),
+                        Some(c) => {
+                            value.push('\\');
+                            value.push(c);
+                        }
+                        None => return Err("unterminated double quote"),
+                    },
+                    Some('$' | '`') => return Err("unescaped $ or ` in double quotes"),
+                    Some(c) => value.push(c),
+                    None => return Err("unterminated double quote"),
+                }
+            },
+            '\\' => match chars.next() {
+                Some(c) => value.push(c),
+                None => return Err("trailing backslash"),
+            },
+            c if c.is_ascii_alphanumeric() || "-_.,:/+@%=".contains(c) => value.push(c),
+            _ => return Err("special characters must be quoted"),
+        }
+    }
+
+    Ok(value)
+}
+
+/// Formats a value so that it reads back unchanged
+fn quote_value(value: &str) -> String {
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+        return value.to_owned();
+    }
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl OsRelease {
+    /// Reads the given os-release file, or the build host's if `path` is `None`
+    pub fn load(path: Option<&Path>) -> io::Result<OsRelease> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => DEFAULT_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .find(|path| path.is_file())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Failed to find os-release"))?,
+        };
+
+        let contents = fs::read_to_string(&path)?;
+        let os_release = OsRelease {
+            lines: contents.lines().map(str::to_owned).collect(),
+        };
+
+        if let Err((line, reason)) = os_release.validate() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid os-release {} line {}: {}", path.display(), line, reason),
+            ));
+        }
+
+        Ok(os_release)
+    }
+
+    /// Checks every line, returning the first invalid line number and the reason
+    fn validate(&self) -> Result<(), (usize, &'static str)> {
+        for (i, line) in self.lines.iter().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or((i + 1, "expected KEY=VALUE"))?;
+            if !is_valid_key(key) {
+                return Err((i + 1, "invalid variable name"));
+            }
+            parse_value(value).map_err(|reason| (i + 1, reason))?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies a `KEY=VALUE` override, replacing any existing assignment of `KEY`
+    pub fn set(&mut self, assignment: &str) -> io::Result<()> {
+        let (key, value) = match assignment.split_once('=') {
+            Some((key, value)) if is_valid_key(key) => (key, value),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid os-release override {:?}, expected KEY=VALUE", assignment),
+                ))
+            }
+        };
+
+        let line = format!("{}={}", key, quote_value(value));
+        let prefix = format!("{}=", key);
+        let mut replaced = false;
+        self.lines.retain_mut(|existing| {
+            if !existing.trim_start().starts_with(&prefix) {
+                return true;
+            }
+            if replaced {
+                return false;
+            }
+            *existing = line.clone();
+            replaced = true;
+            true
+        });
+        if !replaced {
+            self.lines.push(line);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the unquoted value of `key`, if it is set
+    pub fn get(&self, key: &str) -> Option<String> {
+        let prefix = format!("{}=", key);
+        self.lines
+            .iter()
+            .find_map(|line| line.trim().strip_prefix(&prefix))
+            .and_then(|value| parse_value(value).ok())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut contents = self.lines.join("\n");
+        contents.push('\n');
+        contents.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os_release(contents: &str) -> OsRelease {
+        OsRelease {
+            lines: contents.lines().map(str::to_owned).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_quoted_values() {
+        assert_eq!(parse_value("arch").unwrap(), "arch");
+        assert_eq!(parse_value("\"Arch Linux\"").unwrap(), "Arch Linux");
+        assert_eq!(parse_value("'Arch $Linux'").unwrap(), "Arch $Linux");
+        assert_eq!(parse_value(r#""a \"b\" \\ \$c \n""#).unwrap(), r#"a "b" \ $c \n"#);
+        assert_eq!(parse_value(r"Arch\ Linux").unwrap(), "Arch Linux");
+        assert_eq!(parse_value("").unwrap(), "");
+
+        assert!(parse_value("Arch Linux").is_err());
+        assert!(parse_value("\"Arch").is_err());
+        assert!(parse_value("'Arch").is_err());
+        assert!(parse_value("\"$HOME\"").is_err());
+        assert!(parse_value("arch\\").is_err());
+    }
+
+    #[test]
+    fn quote_round_trip() {
+        for value in ["arch", "", "Arch Linux", "\"quoted\"", "$HOME", "back\\slash", "`id`", "it's", "a=b"] {
+            assert_eq!(parse_value(&quote_value(value)).unwrap(), value, "{}", quote_value(value));
+        }
+        assert_eq!(quote_value("6.9.1-arch1"), "6.9.1-arch1");
+    }
+
+    #[test]
+    fn set_replaces_assignment() {
+        let mut os_release = os_release("# comment\nNAME=\"Arch Linux\"\nID=arch\nID=other\n");
+        os_release.set("ID=custom build").unwrap();
+        os_release.set("IMAGE_ID=sigen").unwrap();
+        assert_eq!(
+            String::from_utf8(os_release.to_bytes()).unwrap(),
+            "# comment\nNAME=\"Arch Linux\"\nID=\"custom build\"\nIMAGE_ID=sigen\n"
+        );
+
+        assert!(os_release.set("ID").is_err());
+        assert!(os_release.set("1D=x").is_err());
+    }
+
+    #[test]
+    fn validate_lines() {
+        assert!(os_release("NAME=\"Arch Linux\"\n\n# comment\nID=arch").validate().is_ok());
+        assert_eq!(os_release("ID=arch\nNAME").validate(), Err((2, "expected KEY=VALUE")));
+        assert_eq!(os_release("my-key=x").validate(), Err((1, "invalid variable name")));
+        assert!(os_release("NAME=Arch Linux").validate().is_err());
+    }
+
+    #[test]
+    fn get_unquoted_values() {
+        let os_release = os_release("NAME=\"Arch Linux\"\n  ID=arch\nPRETTY_NAME=Arch Linux\n");
+        assert_eq!(os_release.get("NAME").as_deref(), Some("Arch Linux"));
+        assert_eq!(os_release.get("ID").as_deref(), Some("arch"));
+        assert_eq!(os_release.get("NAM"), None);
+        assert_eq!(os_release.get("VERSION_ID"), None);
+        assert_eq!(os_release.get("PRETTY_NAME"), None);
+    }
+}
@@ -0,0 +1,717 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::ops::Range;
+
+use sha2::{Digest, Sha256};
+
+const PE_SIGNATURE: &[u8; 4] = b"PE\0\0";
+const COFF_HEADER_SIZE: usize = 20;
+const SECTION_HEADER_SIZE: usize = 40;
+
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+
+const WIN_CERT_REVISION_2_0: u16 = 0x0200;
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x0000_0040;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+
+/// Returns the architecture a PE machine type stands for, using the names `uname -m` gives
+pub fn architecture_name(machine: u16) -> Option<&'static str> {
+    match machine {
+        0x014c => Some("x86"),
+        0x8664 => Some("x86_64"),
+        0x01c0 | 0x01c2 | 0x01c4 => Some("arm"),
+        0xaa64 => Some("aarch64"),
+        0x5064 => Some("riscv64"),
+        0x6264 => Some("loongarch64"),
+        _ => None,
+    }
+}
+
+/// Reads the machine type from the COFF header, without checking the rest of the image
+pub fn machine(data: &[u8]) -> Option<u16> {
+    if !data.starts_with(b"MZ") {
+        return None;
+    }
+    let pe_offset = u32::from_le_bytes(data.get(0x3c..0x40)?.try_into().ok()?) as usize;
+    let coff_offset = pe_offset.checked_add(PE_SIGNATURE.len())?;
+    if data.get(pe_offset..coff_offset)? != PE_SIGNATURE {
+        return None;
+    }
+    Some(u16::from_le_bytes(data.get(coff_offset..coff_offset + 2)?.try_into().ok()?))
+}
+
+/// A section table entry of a PE image
+pub struct Section {
+    pub name: String,
+    pub virtual_size: u32,
+    pub virtual_address: u32,
+    pub raw_size: u32,
+    pub raw_offset: u32,
+}
+
+/// An in-memory PE/COFF image, such as the systemd-boot stub
+pub struct PeImage {
+    data: Vec<u8>,
+    coff_offset: usize,
+    optional_header_offset: usize,
+    section_table_offset: usize,
+    data_directories_offset: usize,
+    data_directory_count: usize,
+    sections: Vec<Section>,
+    trailing_data_stripped: bool,
+}
+
+fn malformed(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Malformed PE image: {}", reason))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+impl PeImage {
+    pub fn parse(data: Vec<u8>) -> io::Result<PeImage> {
+        if data.len() < 0x40 || &data[0..2] != b"MZ" {
+            return Err(malformed("missing DOS header"));
+        }
+
+        let pe_offset = read_u32(&data, 0x3c) as usize;
+        let coff_offset = pe_offset + PE_SIGNATURE.len();
+        if data.len() < coff_offset + COFF_HEADER_SIZE || &data[pe_offset..coff_offset] != PE_SIGNATURE {
+            return Err(malformed("missing PE signature"));
+        }
+
+        let section_count = read_u16(&data, coff_offset + 2) as usize;
+        let optional_header_size = read_u16(&data, coff_offset + 16) as usize;
+        let optional_header_offset = coff_offset + COFF_HEADER_SIZE;
+        let section_table_offset = optional_header_offset + optional_header_size;
+        if data.len() < section_table_offset + section_count * SECTION_HEADER_SIZE {
+            return Err(malformed("truncated headers"));
+        }
+
+        let (data_directories_offset, data_directory_count) = match read_u16(&data, optional_header_offset) {
+            PE32_MAGIC if optional_header_size >= 96 => (96, read_u32(&data, optional_header_offset + 92)),
+            PE32_PLUS_MAGIC if optional_header_size >= 112 => (112, read_u32(&data, optional_header_offset + 108)),
+            _ => return Err(malformed("unsupported optional header")),
+        };
+        let data_directory_count = data_directory_count as usize;
+        if data_directories_offset + data_directory_count * 8 > optional_header_size {
+            return Err(malformed("data directories exceed optional header"));
+        }
+
+        let mut sections = Vec::with_capacity(section_count);
+        for i in 0..section_count {
+            let header = &data[section_table_offset + i * SECTION_HEADER_SIZE..][..SECTION_HEADER_SIZE];
+            let name_len = header[..8].iter().position(|&b| b == 0).unwrap_or(8);
+            let section = Section {
+                name: String::from_utf8_lossy(&header[..name_len]).into_owned(),
+                virtual_size: read_u32(header, 8),
+                virtual_address: read_u32(header, 12),
+                raw_size: read_u32(header, 16),
+                raw_offset: read_u32(header, 20),
+            };
+
+            if section.raw_offset as usize + section.raw_size as usize > data.len() {
+                return Err(malformed(&format!("section {} extends past end of file", section.name)));
+            }
+            sections.push(section);
+        }
+
+        let image = PeImage {
+            data,
+            coff_offset,
+            optional_header_offset,
+            section_table_offset,
+            data_directories_offset: optional_header_offset + data_directories_offset,
+            data_directory_count,
+            sections,
+            trailing_data_stripped: false,
+        };
+
+        if image.section_alignment() == 0 || image.file_alignment() == 0 {
+            return Err(malformed("zero section or file alignment"));
+        }
+
+        Ok(image)
+    }
+
+    pub fn machine(&self) -> u16 {
+        read_u16(&self.data, self.coff_offset)
+    }
+
+    /// Returns the COFF header's TimeDateStamp, which sigen sets to the build time
+    pub fn timestamp(&self) -> u32 {
+        read_u32(&self.data, self.coff_offset + 4)
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: u32) {
+        let coff_offset = self.coff_offset;
+        write_u32(&mut self.data, coff_offset + 4, timestamp);
+    }
+
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// Returns the contents of a section, without the padding up to the file alignment
+    pub fn section_data(&self, section: &Section) -> &[u8] {
+        let size = section.raw_size.min(section.virtual_size) as usize;
+        &self.data[section.raw_offset as usize..][..size]
+    }
+
+    /// Returns the contents of the first section with the given name
+    pub fn find_section(&self, name: &str) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| self.section_data(s))
+    }
+
+    fn section_alignment(&self) -> u32 {
+        read_u32(&self.data, self.optional_header_offset + 32)
+    }
+
+    fn file_alignment(&self) -> u32 {
+        read_u32(&self.data, self.optional_header_offset + 36)
+    }
+
+    fn size_of_headers(&self) -> u32 {
+        read_u32(&self.data, self.optional_header_offset + 60)
+    }
+
+    fn checksum_offset(&self) -> usize {
+        self.optional_header_offset + 64
+    }
+
+    fn data_directory_offset(&self, index: usize) -> Option<usize> {
+        if index < self.data_directory_count {
+            Some(self.data_directories_offset + index * 8)
+        } else {
+            None
+        }
+    }
+
+    /// Drops everything after the last section's raw data, which includes any existing signature
+    /// and COFF symbol table, since both would be invalidated by adding sections.
+    fn strip_trailing_data(&mut self) {
+        if self.trailing_data_stripped {
+            return;
+        }
+
+        let end = self
+            .sections
+            .iter()
+            .map(|s| s.raw_offset as usize + s.raw_size as usize)
+            .max()
+            .unwrap_or(0)
+            .max(self.size_of_headers() as usize);
+        self.data.truncate(end);
+
+        if let Some(offset) = self.data_directory_offset(IMAGE_DIRECTORY_ENTRY_SECURITY) {
+            write_u32(&mut self.data, offset, 0);
+            write_u32(&mut self.data, offset + 4, 0);
+        }
+
+        let coff_offset = self.coff_offset;
+        write_u32(&mut self.data, coff_offset + 8, 0);
+        write_u32(&mut self.data, coff_offset + 12, 0);
+
+        self.trailing_data_stripped = true;
+    }
+
+    /// Returns the first suitably aligned virtual address past the end of every existing section
+    fn next_virtual_address(&self) -> u64 {
+        let end = self
+            .sections
+            .iter()
+            .map(|s| s.virtual_address as u64 + (s.virtual_size.max(s.raw_size) as u64).max(1))
+            .max()
+            .unwrap_or(0)
+            .max(read_u32(&self.data, self.optional_header_offset + 56) as u64);
+        align_up(end, self.section_alignment() as u64)
+    }
+
+    /// Appends a new initialized, read-only data section, placed right after the last section in
+    /// memory
+    pub fn add_section(&mut self, name: &str, contents: &[u8]) -> io::Result<()> {
+        if name.is_empty() || name.len() > 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Section name {} must be between 1 and 8 bytes long", name),
+            ));
+        }
+
+        if self.sections.iter().any(|s| s.name == name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Stub already contains a {} section", name),
+            ));
+        }
+
+        let virtual_address = self.next_virtual_address();
+        let virtual_end = virtual_address + contents.len() as u64;
+        if virtual_end > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Section {} does not fit in the image's address space", name),
+            ));
+        }
+        let virtual_address = virtual_address as u32;
+        let virtual_size = contents.len() as u32;
+
+        let header_offset = self.section_table_offset + self.sections.len() * SECTION_HEADER_SIZE;
+        let first_raw_offset = self
+            .sections
+            .iter()
+            .filter(|s| s.raw_size != 0)
+            .map(|s| s.raw_offset as usize)
+            .min()
+            .unwrap_or(usize::MAX)
+            .min(self.size_of_headers() as usize);
+        if header_offset + SECTION_HEADER_SIZE > first_raw_offset {
+            return Err(io::Error::other(
+                format!("No space left in the stub's section table for section {}", name),
+            ));
+        }
+
+        self.strip_trailing_data();
+
+        let file_alignment = self.file_alignment() as u64;
+        let raw_offset = align_up(self.data.len() as u64, file_alignment);
+        let raw_size = align_up(contents.len() as u64, file_alignment);
+        if raw_offset + raw_size > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Image exceeds 4 GiB"));
+        }
+
+        let (raw_offset, raw_size) = if contents.is_empty() {
+            (0, 0)
+        } else {
+            self.data.resize(raw_offset as usize, 0);
+            self.data.extend_from_slice(contents);
+            self.data.resize((raw_offset + raw_size) as usize, 0);
+            (raw_offset as u32, raw_size as u32)
+        };
+
+        let mut header = [0u8; SECTION_HEADER_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_u32(&mut header, 8, virtual_size);
+        write_u32(&mut header, 12, virtual_address);
+        write_u32(&mut header, 16, raw_size);
+        write_u32(&mut header, 20, raw_offset);
+        write_u32(&mut header, 36, IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ);
+        self.data[header_offset..header_offset + SECTION_HEADER_SIZE].copy_from_slice(&header);
+
+        self.sections.push(Section {
+            name: name.to_owned(),
+            virtual_size,
+            virtual_address,
+            raw_size,
+            raw_offset,
+        });
+
+        let coff_offset = self.coff_offset;
+        write_u16(&mut self.data, coff_offset + 2, self.sections.len() as u16);
+
+        let opt = self.optional_header_offset;
+        let initialized_data = read_u32(&self.data, opt + 8).saturating_add(raw_size);
+        write_u32(&mut self.data, opt + 8, initialized_data);
+
+        let size_of_image = align_up(virtual_end, self.section_alignment() as u64).min(u32::MAX as u64) as u32;
+        if size_of_image > read_u32(&self.data, opt + 56) {
+            write_u32(&mut self.data, opt + 56, size_of_image);
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the contents of an existing section in place, failing if they don't fit in the
+    /// space it already takes up in the file and in memory
+    pub fn replace_section(&mut self, name: &str, contents: &[u8]) -> io::Result<()> {
+        let index = self
+            .sections
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Stub has no {} section", name)))?;
+
+        let section = &self.sections[index];
+        let next_virtual_address = self
+            .sections
+            .iter()
+            .map(|s| s.virtual_address)
+            .filter(|&address| address > section.virtual_address)
+            .min()
+            .unwrap_or(u32::MAX);
+        let capacity = section.raw_size.min(next_virtual_address - section.virtual_address) as usize;
+        if contents.len() > capacity {
+            return Err(io::Error::other(format!(
+                "New {} section is {} bytes, but the stub only has room for {}",
+                name,
+                contents.len(),
+                capacity
+            )));
+        }
+
+        let raw_offset = section.raw_offset as usize;
+        let raw_size = section.raw_size as usize;
+        self.data[raw_offset..raw_offset + raw_size].fill(0);
+        self.data[raw_offset..raw_offset + contents.len()].copy_from_slice(contents);
+
+        let header_offset = self.section_table_offset + index * SECTION_HEADER_SIZE;
+        write_u32(&mut self.data, header_offset + 8, contents.len() as u32);
+        self.sections[index].virtual_size = contents.len() as u32;
+
+        Ok(())
+    }
+
+    fn security_directory(&self) -> Option<Range<usize>> {
+        let offset = self.data_directory_offset(IMAGE_DIRECTORY_ENTRY_SECURITY)?;
+        let start = read_u32(&self.data, offset) as usize;
+        let size = read_u32(&self.data, offset + 4) as usize;
+        if start == 0 || size == 0 {
+            None
+        } else {
+            Some(start..start.saturating_add(size))
+        }
+    }
+
+    /// Returns the PKCS#7 SignedData blobs stored in the image's certificate table
+    pub fn signatures(&self) -> io::Result<Vec<&[u8]>> {
+        let table = match self.security_directory() {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+        if table.end > self.data.len() {
+            return Err(malformed("certificate table extends past end of file"));
+        }
+
+        let mut signatures = Vec::new();
+        let mut offset = table.start;
+        while offset + 8 <= table.end {
+            let length = read_u32(&self.data, offset) as usize;
+            let revision = read_u16(&self.data, offset + 4);
+            let certificate_type = read_u16(&self.data, offset + 6);
+            if length < 8 || offset + length > table.end {
+                return Err(malformed("invalid certificate table entry"));
+            }
+
+            if revision == WIN_CERT_REVISION_2_0 && certificate_type == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+                signatures.push(&self.data[offset + 8..offset + length]);
+            }
+            offset += align_up(length as u64, 8) as usize;
+        }
+
+        Ok(signatures)
+    }
+
+    /// Computes the SHA-256 Authenticode digest of the image, which covers everything except the
+    /// checksum, the security directory entry and the certificate table itself
+    pub fn authenticode_digest(&self) -> io::Result<Vec<u8>> {
+        let security_entry = self
+            .data_directory_offset(IMAGE_DIRECTORY_ENTRY_SECURITY)
+            .ok_or_else(|| malformed("no security directory entry"))?;
+        let checksum_offset = self.checksum_offset();
+        let size_of_headers = self.size_of_headers() as usize;
+        if size_of_headers > self.data.len() || security_entry + 8 > size_of_headers {
+            return Err(malformed("headers extend past end of file"));
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(&self.data[..checksum_offset]);
+        hasher.update(&self.data[checksum_offset + 4..security_entry]);
+        hasher.update(&self.data[security_entry + 8..size_of_headers]);
+
+        let mut sections: Vec<&Section> = self.sections.iter().filter(|s| s.raw_size != 0).collect();
+        sections.sort_by_key(|s| s.raw_offset);
+
+        let mut hashed_end = size_of_headers;
+        for section in sections {
+            let start = section.raw_offset as usize;
+            let end = start + section.raw_size as usize;
+            hasher.update(&self.data[start..end]);
+            hashed_end = hashed_end.max(end);
+        }
+
+        let data_end = match self.security_directory() {
+            Some(certificates) if certificates.end > self.data.len() => {
+                return Err(malformed("certificate table extends past end of file"));
+            }
+            Some(certificates) => certificates.start,
+            None => self.data.len(),
+        };
+        if data_end > hashed_end {
+            hasher.update(&self.data[hashed_end..data_end]);
+        }
+
+        // The certificate table is 8-byte aligned, so unsigned images are hashed as if they were
+        // already padded for it.
+        if self.security_directory().is_none() && data_end % 8 != 0 {
+            hasher.update(&[0u8; 8][..8 - data_end % 8]);
+        }
+
+        Ok(hasher.finalize().to_vec())
+    }
+
+    /// Appends a PKCS#7 SignedData blob as the image's only certificate table entry
+    pub fn append_certificate(&mut self, signed_data: &[u8]) -> io::Result<()> {
+        self.strip_trailing_data();
+
+        let security_entry = self
+            .data_directory_offset(IMAGE_DIRECTORY_ENTRY_SECURITY)
+            .ok_or_else(|| malformed("no security directory entry"))?;
+
+        let length = 8 + signed_data.len();
+        let padded_length = align_up(length as u64, 8) as usize;
+        let offset = align_up(self.data.len() as u64, 8) as usize;
+        if offset + padded_length > u32::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Image exceeds 4 GiB"));
+        }
+
+        self.data.resize(offset, 0);
+        self.data.extend_from_slice(&(length as u32).to_le_bytes());
+        self.data.extend_from_slice(&WIN_CERT_REVISION_2_0.to_le_bytes());
+        self.data.extend_from_slice(&WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+        self.data.extend_from_slice(signed_data);
+        self.data.resize(offset + padded_length, 0);
+
+        write_u32(&mut self.data, security_entry, offset as u32);
+        write_u32(&mut self.data, security_entry + 4, padded_length as u32);
+
+        Ok(())
+    }
+
+    /// Returns the image contents, with an updated checksum
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        let checksum_offset = self.checksum_offset();
+        write_u32(&mut self.data, checksum_offset, 0);
+        let checksum = checksum(&self.data, checksum_offset);
+        write_u32(&mut self.data, checksum_offset, checksum);
+        self.data
+    }
+}
+
+/// Computes the PE image checksum, skipping the checksum field itself
+fn checksum(data: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum: u64 = 0;
+
+    for (i, chunk) in data.chunks(2).enumerate() {
+        let offset = i * 2;
+        if offset >= checksum_offset && offset < checksum_offset + 4 {
+            continue;
+        }
+
+        let word = if chunk.len() == 2 {
+            u16::from_le_bytes([chunk[0], chunk[1]])
+        } else {
+            chunk[0] as u16
+        };
+
+        sum += word as u64;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum = (sum & 0xffff) + (sum >> 16);
+    (sum as u32).wrapping_add(data.len() as u32)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const CHECKSUM_OFFSET: usize = 0x58 + 64;
+
+    /// Builds a PE32+ image with a single .text section and room in the section table for three more
+    pub(crate) fn test_image() -> Vec<u8> {
+        let mut data = vec![0u8; 0x400];
+        data[..2].copy_from_slice(b"MZ");
+        write_u32(&mut data, 0x3c, 0x40);
+        data[0x40..0x44].copy_from_slice(PE_SIGNATURE);
+
+        write_u16(&mut data, 0x44, 0x8664);
+        write_u16(&mut data, 0x44 + 2, 1);
+        write_u16(&mut data, 0x44 + 16, 112 + 16 * 8);
+
+        let opt = 0x58;
+        write_u16(&mut data, opt, PE32_PLUS_MAGIC);
+        write_u32(&mut data, opt + 32, 0x1000);
+        write_u32(&mut data, opt + 36, 0x200);
+        write_u32(&mut data, opt + 56, 0x2000);
+        write_u32(&mut data, opt + 60, 0x200);
+        write_u32(&mut data, opt + 108, 16);
+
+        let header = opt + 112 + 16 * 8;
+        data[header..header + 5].copy_from_slice(b".text");
+        write_u32(&mut data, header + 8, 0x10);
+        write_u32(&mut data, header + 12, 0x1000);
+        write_u32(&mut data, header + 16, 0x200);
+        write_u32(&mut data, header + 20, 0x200);
+        data[0x200..0x210].copy_from_slice(b"\xc3stub code here!");
+
+        data
+    }
+
+    /// Returns the contents of a section without its file alignment padding
+    fn contents<'a>(image: &'a PeImage, name: &str) -> &'a [u8] {
+        let section = image.sections.iter().find(|s| s.name == name).unwrap();
+        &image.data[section.raw_offset as usize..][..section.virtual_size as usize]
+    }
+
+    fn names(image: &PeImage) -> Vec<&str> {
+        image.sections.iter().map(|s| s.name.as_str()).collect()
+    }
+
+    #[test]
+    fn add_section_round_trip() {
+        let mut image = PeImage::parse(test_image()).unwrap();
+        image.add_section(".cmdline", b"quiet\0").unwrap();
+        image.add_section(".linux", &[0xaa; 0x300]).unwrap();
+
+        let image = PeImage::parse(image.into_bytes()).unwrap();
+        assert_eq!(names(&image), [".text", ".cmdline", ".linux"]);
+        assert_eq!(contents(&image, ".cmdline"), b"quiet\0");
+        assert_eq!(contents(&image, ".linux"), [0xaa; 0x300]);
+        assert!(image.sections.iter().all(|s| s.raw_offset % 0x200 == 0));
+        assert_eq!(read_u32(&image.data, 0x58 + 56), 0x4000);
+    }
+
+    #[test]
+    fn add_section_after_last_section() {
+        let mut image = PeImage::parse(test_image()).unwrap();
+        image.add_section(".cmdline", b"quiet\0").unwrap();
+        image.add_section(".linux", &[0xaa; 0x1001]).unwrap();
+        image.add_section(".initrd", b"").unwrap();
+
+        let addresses: Vec<u32> = image.sections.iter().map(|s| s.virtual_address).collect();
+        assert_eq!(addresses, [0x1000, 0x2000, 0x3000, 0x5000]);
+
+        // Space the stub reserves past its last section, such as its .bss, is left alone
+        let mut data = test_image();
+        write_u32(&mut data, 0x58 + 56, 0x6000);
+        let mut image = PeImage::parse(data).unwrap();
+        image.add_section(".cmdline", b"quiet\0").unwrap();
+        assert_eq!(image.sections[1].virtual_address, 0x6000);
+    }
+
+    #[test]
+    fn add_section_rejects_invalid_names() {
+        let mut image = PeImage::parse(test_image()).unwrap();
+        assert_eq!(
+            image.add_section(".text", b"").unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            image.add_section(".toolong!", b"").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(image.add_section("", b"").is_err());
+    }
+
+    #[test]
+    fn add_section_fails_when_section_table_is_full() {
+        let mut image = PeImage::parse(test_image()).unwrap();
+        for name in [".a", ".b", ".c"] {
+            image.add_section(name, b"x").unwrap();
+        }
+        assert!(image.add_section(".d", b"x").is_err());
+    }
+
+    #[test]
+    fn into_bytes_writes_checksum() {
+        let mut image = PeImage::parse(test_image()).unwrap();
+        image.add_section(".osrel", b"ID=test\n").unwrap();
+        let data = image.into_bytes();
+
+        let stored = read_u32(&data, CHECKSUM_OFFSET);
+        assert_ne!(stored, 0);
+        assert_eq!(checksum(&data, CHECKSUM_OFFSET), stored);
+    }
+
+    #[test]
+    fn authenticode_digest_skips_checksum_and_certificates() {
+        let data = test_image();
+        let security_entry = 0x58 + 112 + IMAGE_DIRECTORY_ENTRY_SECURITY * 8;
+        let mut hasher = Sha256::new();
+        hasher.update(&data[..CHECKSUM_OFFSET]);
+        hasher.update(&data[CHECKSUM_OFFSET + 4..security_entry]);
+        hasher.update(&data[security_entry + 8..]);
+        let expected = hasher.finalize().to_vec();
+
+        let mut image = PeImage::parse(data).unwrap();
+        assert_eq!(image.authenticode_digest().unwrap(), expected);
+
+        image.append_certificate(b"not really a signature").unwrap();
+        let image = PeImage::parse(image.into_bytes()).unwrap();
+        assert_eq!(image.authenticode_digest().unwrap(), expected);
+
+        let mut data = test_image();
+        data[0x200] ^= 1;
+        assert_ne!(PeImage::parse(data).unwrap().authenticode_digest().unwrap(), expected);
+    }
+
+    #[test]
+    fn authenticode_digest_pads_unsigned_images() {
+        let mut data = test_image();
+        data.extend_from_slice(b"abc");
+        let digest = PeImage::parse(data.clone()).unwrap().authenticode_digest().unwrap();
+
+        data.resize(data.len().next_multiple_of(8), 0);
+        assert_eq!(PeImage::parse(data).unwrap().authenticode_digest().unwrap(), digest);
+    }
+
+    #[test]
+    fn append_certificate_round_trip() {
+        let mut image = PeImage::parse(test_image()).unwrap();
+        assert!(image.signatures().unwrap().is_empty());
+
+        image.append_certificate(b"not really a signature").unwrap();
+        let image = PeImage::parse(image.into_bytes()).unwrap();
+        assert_eq!(image.signatures().unwrap(), [&b"not really a signature"[..]]);
+        assert_eq!(image.data.len() % 8, 0);
+    }
+
+    #[test]
+    fn authenticode_digest_rejects_certificates_past_end_of_file() {
+        let mut data = test_image();
+        let security_entry = 0x58 + 112 + IMAGE_DIRECTORY_ENTRY_SECURITY * 8;
+        write_u32(&mut data, security_entry, 0x3f8);
+        write_u32(&mut data, security_entry + 4, 0x100);
+
+        let image = PeImage::parse(data).unwrap();
+        assert_eq!(image.authenticode_digest().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(image.signatures().is_err());
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+use structopt::StructOpt;
+
+use crate::atomic;
+use crate::backup;
+use crate::pe::PeImage;
+use crate::verify;
+
+/// Restores an output from one of the backups kept with --backup-dir
+#[derive(Clone, StructOpt)]
+pub struct RollbackArgs {
+    /// Path to the output to restore
+    output: PathBuf,
+    /// Directory holding the backups
+    #[structopt(long)]
+    backup_dir: PathBuf,
+    /// List the available backups instead of restoring one
+    #[structopt(short, long)]
+    list: bool,
+    /// Number of the backup to restore, as shown by --list (1 is the newest)
+    #[structopt(short, long, default_value = "1")]
+    number: usize,
+    /// Path to a trusted certificate, in PEM or DER form
+    #[structopt(short, long, required_unless_one = &["db", "list"])]
+    cert: Vec<PathBuf>,
+    /// Path to an EFI signature list (such as db.esl, or the db variable in efivarfs)
+    #[structopt(short, long)]
+    db: Vec<PathBuf>,
+}
+
+pub fn rollback(args: RollbackArgs) -> io::Result<()> {
+    let backups = backup::list(&args.backup_dir, &args.output)?;
+    if backups.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No backups of {} in {}", args.output.display(), args.backup_dir.display()),
+        ));
+    }
+
+    if args.list {
+        println!("Backups of {}:", args.output.display());
+        println!("    {:>3}  {:<24}  {:<15}  Path", "#", "Kernel", "Built (UTC)");
+        for (i, entry) in backups.iter().enumerate() {
+            let (release, built) = match fs::read(&entry.path).and_then(PeImage::parse) {
+                Ok(image) => (
+                    backup::kernel_release(&image).unwrap_or_else(|| "unknown".to_owned()),
+                    backup::timestamp(UNIX_EPOCH + Duration::from_secs(image.timestamp() as u64)),
+                ),
+                Err(_) => ("invalid image".to_owned(), String::new()),
+            };
+            println!("    {:>3}  {:<24}  {:<15}  {}", i + 1, release, built, entry.path.display());
+        }
+        return Ok(());
+    }
+
+    let entry = args.number.checked_sub(1).and_then(|i| backups.get(i)).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No backup number {}, there are {}", args.number, backups.len()),
+        )
+    })?;
+
+    let data = fs::read(&entry.path)?;
+    let image = PeImage::parse(data.clone())?;
+    if image.find_section(".linux").is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} does not contain a kernel", entry.path.display()),
+        ));
+    }
+
+    let trust = verify::load_trust_store(&args.cert, &args.db)?;
+    println!(
+        "{}: {}",
+        entry.path.display(),
+        verify::check_image(&image, &entry.path, &trust)?
+    );
+
+    print!("Restoring {} to {}...", entry.path.display(), args.output.display());
+    io::stdout().flush()?;
+    atomic::write(&args.output, &data)?;
+    println!(" done");
+
+    Ok(())
+}
@@ -0,0 +1,149 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The line that starts every SBAT section, declaring the version of the format
+const SBAT_HEADER: &str = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md";
+
+/// Checks an SBAT line, returning its component name
+fn component(line: &str) -> Result<&str, &'static str> {
+    let fields: Vec<&str> = line.split(',').collect();
+    if fields.len() < 2 || fields.len() > 6 {
+        return Err("expected component,generation[,vendor,package,version,url]");
+    }
+
+    let name = fields[0];
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err("invalid component name");
+    }
+    match fields[1].parse::<u32>() {
+        Ok(generation) if generation > 0 => {}
+        _ => return Err("generation must be a positive number"),
+    }
+    if fields.iter().any(|field| field.contains('"')) {
+        return Err("quoted fields are not supported");
+    }
+
+    Ok(name)
+}
+
+/// Appends the lines in `text` to `lines`, rejecting a component that is already there with a
+/// different entry
+fn add_lines(lines: &mut Vec<String>, text: &str, source: &str) -> io::Result<()> {
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |reason: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid SBAT data in {} line {}: {}", source, i + 1, reason),
+            )
+        };
+        let name = component(line).map_err(|reason| invalid(reason.to_owned()))?;
+
+        match lines.iter().find(|existing| component(existing) == Ok(name)) {
+            Some(existing) if existing == line => {}
+            Some(existing) => return Err(invalid(format!("{} is already listed as {:?}", name, existing))),
+            None => lines.push(line.to_owned()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Merges the SBAT data in `path` with the stub's own `.sbat` section, if it has one
+pub fn merge(stub_sbat: Option<&[u8]>, path: &Path) -> io::Result<Vec<u8>> {
+    let mut lines = Vec::new();
+
+    if let Some(data) = stub_sbat {
+        let text = String::from_utf8_lossy(data);
+        add_lines(&mut lines, text.trim_end_matches('\0'), "the stub")?;
+    }
+    add_lines(&mut lines, &fs::read_to_string(path)?, &path.display().to_string())?;
+
+    // Shim expects the format version to come first
+    let header = match lines.iter().position(|line| component(line) == Ok("sbat")) {
+        Some(index) => lines.remove(index),
+        None => SBAT_HEADER.to_owned(),
+    };
+    lines.insert(0, header);
+
+    let mut merged = lines.join("\n");
+    merged.push('\n');
+    Ok(merged.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STUB_SBAT: &str = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n\
+                             systemd,1,The systemd Developers,systemd,255,https://systemd.io/\n";
+
+    fn merge_text(stub_sbat: Option<&str>, extra: &str) -> io::Result<String> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        io::Write::write_all(&mut file, extra.as_bytes()).unwrap();
+        merge(stub_sbat.map(str::as_bytes), file.path()).map(|merged| String::from_utf8(merged).unwrap())
+    }
+
+    #[test]
+    fn merge_with_stub() {
+        let mut stub_sbat = STUB_SBAT.to_owned().into_bytes();
+        stub_sbat.extend_from_slice(&[0; 16]);
+        let stub_sbat = String::from_utf8(stub_sbat).unwrap();
+
+        let line = "linux.arch,1,Arch Linux,linux,6.9.1,https://archlinux.org\n";
+        assert_eq!(merge_text(Some(&stub_sbat), line).unwrap(), format!("{}{}", STUB_SBAT, line));
+    }
+
+    #[test]
+    fn merge_adds_header_first() {
+        let merged = merge_text(None, "linux,1\n\n  sbat,1  \n").unwrap();
+        assert_eq!(merged, "sbat,1\nlinux,1\n");
+
+        let merged = merge_text(None, "linux,1\n").unwrap();
+        assert_eq!(merged, format!("{}\nlinux,1\n", SBAT_HEADER));
+    }
+
+    #[test]
+    fn merge_skips_duplicates() {
+        let line = "systemd,1,The systemd Developers,systemd,255,https://systemd.io/";
+        assert_eq!(merge_text(Some(STUB_SBAT), line).unwrap(), STUB_SBAT);
+    }
+
+    #[test]
+    fn merge_rejects_conflicts() {
+        let err = merge_text(Some(STUB_SBAT), "systemd,2,The systemd Developers,systemd,256,https://systemd.io/\n")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reject_invalid_lines() {
+        for line in ["linux", "linux,0", "linux,x", "linux kernel,1", "linux,1,\"Arch\"", ",1", "a,1,2,3,4,5,6"] {
+            assert!(merge_text(None, line).is_err(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const FILE_HEADER_SIZE: usize = 14;
+const INFO_HEADER_SIZE: usize = 40;
+const MAX_DIMENSION: u32 = 8192;
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+/// Reads a boot splash image, making sure it is a BMP that systemd-stub can display
+pub fn load(path: &Path) -> io::Result<Vec<u8>> {
+    let data = fs::read(path)?;
+
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported splash image {}: {}", path.display(), reason),
+        )
+    };
+    let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+    let u32_at =
+        |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+
+    if data.len() < FILE_HEADER_SIZE + INFO_HEADER_SIZE || &data[0..2] != b"BM" {
+        return Err(invalid("not a BMP file"));
+    }
+
+    let pixel_offset = u32_at(10) as usize;
+    let header_size = u32_at(14) as usize;
+    let width = u32_at(18) as i32;
+    let height = u32_at(22) as i32;
+    let planes = u16_at(26);
+    let depth = u16_at(28);
+    let compression = u32_at(30);
+
+    if header_size < INFO_HEADER_SIZE || planes != 1 {
+        return Err(invalid("unsupported BMP header"));
+    }
+    if depth != 24 && depth != 32 {
+        return Err(invalid(&format!("{}-bit images are not supported, use 24 or 32 bits", depth)));
+    }
+    if compression != BI_RGB && !(compression == BI_BITFIELDS && depth == 32) {
+        return Err(invalid("compressed images are not supported"));
+    }
+    if width <= 0 || height <= 0 {
+        return Err(invalid("top-down or empty images are not supported"));
+    }
+    if width as u32 > MAX_DIMENSION || height as u32 > MAX_DIMENSION {
+        return Err(invalid(&format!(
+            "{}x{} exceeds the maximum of {}x{}",
+            width, height, MAX_DIMENSION, MAX_DIMENSION
+        )));
+    }
+
+    let row_size = (width as usize * depth as usize).div_ceil(32) * 4;
+    if pixel_offset < FILE_HEADER_SIZE + header_size || pixel_offset + row_size * height as usize > data.len() {
+        return Err(invalid("truncated pixel data"));
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an uncompressed bottom-up BMP with black pixels
+    fn bmp(width: i32, height: i32, depth: u16) -> Vec<u8> {
+        let pixel_offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+        let row_size = (width.unsigned_abs() as usize * depth as usize).div_ceil(32) * 4;
+        let mut data = vec![0u8; pixel_offset + row_size * height.unsigned_abs() as usize];
+        data[0..2].copy_from_slice(b"BM");
+        let file_size = data.len() as u32;
+        data[2..6].copy_from_slice(&file_size.to_le_bytes());
+        data[10..14].copy_from_slice(&(pixel_offset as u32).to_le_bytes());
+        data[14..18].copy_from_slice(&(INFO_HEADER_SIZE as u32).to_le_bytes());
+        data[18..22].copy_from_slice(&width.to_le_bytes());
+        data[22..26].copy_from_slice(&height.to_le_bytes());
+        data[26..28].copy_from_slice(&1u16.to_le_bytes());
+        data[28..30].copy_from_slice(&depth.to_le_bytes());
+        data
+    }
+
+    fn load_bytes(data: &[u8]) -> io::Result<Vec<u8>> {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("splash.bmp");
+        fs::write(&path, data).unwrap();
+        load(&path)
+    }
+
+    fn rejects(data: &[u8], reason: &str) {
+        let err = load_bytes(data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().ends_with(reason), "{}", err);
+    }
+
+    #[test]
+    fn load_accepts_24_bit_image() {
+        let data = bmp(3, 2, 24);
+        assert_eq!(load_bytes(&data).unwrap(), data);
+    }
+
+    #[test]
+    fn load_rejects_unsupported_images() {
+        rejects(b"GIF89a", "not a BMP file");
+        rejects(&bmp(3, 2, 8), "8-bit images are not supported, use 24 or 32 bits");
+
+        let mut compressed = bmp(3, 2, 24);
+        compressed[30..34].copy_from_slice(&1u32.to_le_bytes());
+        rejects(&compressed, "compressed images are not supported");
+
+        rejects(&bmp(3, -2, 24), "top-down or empty images are not supported");
+        rejects(&bmp(0, 2, 24), "top-down or empty images are not supported");
+        rejects(&bmp(8193, 1, 24), "8193x1 exceeds the maximum of 8192x8192");
+
+        let data = bmp(3, 2, 24);
+        rejects(&data[..data.len() - 1], "truncated pixel data");
+    }
+}
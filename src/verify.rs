@@ -0,0 +1,224 @@
+// Copyright © 2019-2020 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use der::Decode;
+use structopt::StructOpt;
+use x509_cert::Certificate;
+
+use crate::authenticode::{self, TrustStore};
+use crate::pe::PeImage;
+
+const EFI_CERT_X509_GUID: [u8; 16] = [
+    0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72,
+];
+const EFI_CERT_SHA256_GUID: [u8; 16] = [
+    0x26, 0x16, 0xc4, 0xc1, 0x4c, 0x50, 0x92, 0x40, 0xac, 0xa9, 0x41, 0xf9, 0x36, 0x93, 0x43, 0x28,
+];
+
+/// Checks the signature of an EFI executable against trusted certificates
+#[derive(Clone, StructOpt)]
+pub struct VerifyArgs {
+    /// Path to the EFI executable to verify
+    image: PathBuf,
+    /// Path to a trusted certificate, in PEM or DER form
+    #[structopt(short, long, required_unless = "db")]
+    cert: Vec<PathBuf>,
+    /// Path to an EFI signature list (such as db.esl, or the db variable in efivarfs)
+    #[structopt(short, long)]
+    db: Vec<PathBuf>,
+}
+
+/// Parses a sequence of EFI_SIGNATURE_LISTs, returning None if it is malformed
+fn parse_signature_lists(mut lists: &[u8]) -> Option<TrustStore> {
+    let read_u32 = |bytes: &[u8], offset: usize| {
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as usize
+    };
+
+    let mut trust = TrustStore::default();
+    while !lists.is_empty() {
+        if lists.len() < 28 {
+            return None;
+        }
+
+        let list_size = read_u32(lists, 16);
+        let header_size = read_u32(lists, 20);
+        let signature_size = read_u32(lists, 24);
+        if list_size > lists.len() || signature_size < 16 || 28 + header_size > list_size {
+            return None;
+        }
+
+        let signature_type = &lists[..16];
+        for entry in lists[28 + header_size..list_size].chunks(signature_size) {
+            if entry.len() != signature_size {
+                return None;
+            }
+
+            let data = &entry[16..];
+            if signature_type == EFI_CERT_X509_GUID {
+                trust.certificates.push(Certificate::from_der(data).ok()?);
+            } else if signature_type == EFI_CERT_SHA256_GUID {
+                trust.digests.push(data.to_vec());
+            }
+        }
+
+        lists = &lists[list_size..];
+    }
+
+    Some(trust)
+}
+
+/// Adds the X.509 certificates and SHA-256 hashes from an EFI_SIGNATURE_LIST sequence to `trust`
+fn load_signature_lists(path: &Path, trust: &mut TrustStore) -> io::Result<()> {
+    let data = fs::read(path)?;
+
+    // Variables read from efivarfs are prefixed with their 4-byte attributes, even once copied
+    // elsewhere, so try again without them if the file doesn't parse as is.
+    let lists = parse_signature_lists(&data)
+        .or_else(|| data.get(4..).and_then(parse_signature_lists))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Malformed EFI signature list {}", path.display()),
+            )
+        })?;
+
+    trust.certificates.extend(lists.certificates);
+    trust.digests.extend(lists.digests);
+    Ok(())
+}
+
+/// Builds a trust store from certificate files and EFI signature lists
+pub fn load_trust_store(certs: &[PathBuf], dbs: &[PathBuf]) -> io::Result<TrustStore> {
+    let mut trust = TrustStore::default();
+    for path in certs {
+        trust.certificates.push(authenticode::load_certificate(path)?);
+    }
+    for path in dbs {
+        load_signature_lists(path, &mut trust)?;
+    }
+    Ok(trust)
+}
+
+pub fn verify(args: VerifyArgs) -> io::Result<()> {
+    let trust = load_trust_store(&args.cert, &args.db)?;
+
+    let image = PeImage::parse(fs::read(&args.image)?)?;
+    println!("{}: {}", args.image.display(), check_image(&image, &args.image, &trust)?);
+    Ok(())
+}
+
+/// Checks that an image is trusted, either by its hash or by one of its signatures, returning how
+pub fn check_image(image: &PeImage, path: &Path, trust: &TrustStore) -> io::Result<String> {
+    let digest = image.authenticode_digest()?;
+
+    if trust.digests.contains(&digest) {
+        return Ok(format!("image hash {} is trusted", authenticode::hex(&digest)));
+    }
+
+    let signatures = image.signatures()?;
+    if signatures.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not signed", path.display()),
+        ));
+    }
+
+    let mut failure = None;
+    for signature in signatures {
+        match authenticode::verify(signature, &digest, trust) {
+            Ok(signer) => return Ok(format!("signed by {}", signer)),
+            Err(err) => failure = Some(err),
+        }
+    }
+
+    Err(failure.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use der::Encode;
+    use sha2::{Digest, Sha256};
+
+    fn test_certificate() -> Certificate {
+        authenticode::load_certificate(&Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/test.crt")).unwrap()
+    }
+
+    /// Builds an EFI_SIGNATURE_LIST of `signature_type`, with a zero owner GUID for each entry
+    fn signature_list(signature_type: &[u8; 16], entries: &[&[u8]]) -> Vec<u8> {
+        let signature_size = 16 + entries[0].len();
+        let mut list = signature_type.to_vec();
+        list.extend_from_slice(&((28 + signature_size * entries.len()) as u32).to_le_bytes());
+        list.extend_from_slice(&0u32.to_le_bytes());
+        list.extend_from_slice(&(signature_size as u32).to_le_bytes());
+        for entry in entries {
+            list.extend_from_slice(&[0; 16]);
+            list.extend_from_slice(entry);
+        }
+        list
+    }
+
+    fn test_lists() -> Vec<u8> {
+        let certificate = test_certificate().to_der().unwrap();
+        let mut lists = signature_list(&EFI_CERT_X509_GUID, &[&certificate]);
+        lists.extend(signature_list(
+            &EFI_CERT_SHA256_GUID,
+            &[&Sha256::digest(b"image"), &Sha256::digest(b"other image")],
+        ));
+        lists
+    }
+
+    #[test]
+    fn parse_signature_lists_reads_certificates_and_hashes() {
+        let trust = parse_signature_lists(&test_lists()).unwrap();
+        assert_eq!(trust.certificates, [test_certificate()]);
+        assert_eq!(trust.digests, [Sha256::digest(b"image").to_vec(), Sha256::digest(b"other image").to_vec()]);
+    }
+
+    #[test]
+    fn load_signature_lists_skips_attributes_prefix() {
+        // EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS
+        let mut prefixed = 7u32.to_le_bytes().to_vec();
+        prefixed.extend(test_lists());
+        assert!(parse_signature_lists(&prefixed).is_none());
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("db-d719b2cb-3d3a-4596-a3bc-dad00e67656f");
+        fs::write(&path, &prefixed).unwrap();
+
+        let mut trust = TrustStore::default();
+        load_signature_lists(&path, &mut trust).unwrap();
+        assert_eq!(trust.certificates, [test_certificate()]);
+        assert_eq!(trust.digests.len(), 2);
+    }
+
+    #[test]
+    fn load_signature_lists_rejects_truncated_lists() {
+        let lists = test_lists();
+        assert!(parse_signature_lists(&lists[..lists.len() - 1]).is_none());
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("db.esl");
+        fs::write(&path, &lists[..lists.len() - 1]).unwrap();
+
+        let err = load_signature_lists(&path, &mut TrustStore::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}